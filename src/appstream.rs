@@ -0,0 +1,585 @@
+use crate::desktop;
+use crate::metadata::Metadata;
+use std::fs;
+use std::path::Path;
+
+/// Keys accepted in the `appstream:` block. `description` and `screenshot` may repeat.
+const APPSTREAM_KEYS: &[&str] = &[
+    "id",
+    "type",
+    "name",
+    "summary",
+    "description",
+    "metadata_license",
+    "launchable",
+    "screenshot",
+    "changelog",
+];
+
+/// Licenses AppStream accepts for the metadata itself.
+const METADATA_LICENSES: &[&str] = &[
+    "CC0-1.0",
+    "CC-BY-3.0",
+    "CC-BY-4.0",
+    "CC-BY-SA-3.0",
+    "CC-BY-SA-4.0",
+    "GFDL-1.3",
+    "MIT",
+    "FSFAP",
+    "0BSD",
+];
+
+/// Tags every component needs, checked by [`validate`].
+const REQUIRED_TAGS: &[&str] = &[
+    "id",
+    "name",
+    "summary",
+    "metadata_license",
+    "project_license",
+    "description",
+];
+
+/// One `<release>`, from the changelog.
+struct Release {
+    version: String,
+    date: String,
+    changes: Vec<String>,
+}
+
+/// Package path of the metainfo file for component `id`.
+pub fn metainfo_path(id: &str) -> String {
+    format!("/usr/share/metainfo/{}.metainfo.xml", id)
+}
+
+/// Generate the AppStream metainfo described by the `appstream:` block, or `None` without one.
+/// Returns the component ID and the XML.
+///
+///   appstream: {
+///       id = io.github.twincan.TwinCAN
+///       summary = Browse CAN bus traffic
+///       description = TwinCAN shows the frames of two CAN buses side by side.
+///       screenshot = https://example.com/main.png Main window
+///       changelog = TwinCAN/CHANGELOG.md
+///   }
+pub fn generate(
+    metadata: &Metadata,
+) -> Result<Option<(String, String)>, Box<dyn std::error::Error>> {
+    if metadata.appstream.is_empty() {
+        return Ok(None);
+    }
+
+    let mut fields: Vec<(&str, String)> = Vec::new();
+    for (line_no, line) in &metadata.appstream {
+        let (key, value) = line
+            .split_once('=')
+            .map(|(key, value)| (key.trim(), value.trim().trim_matches('"')))
+            .ok_or_else(|| format!("line {}: expected `key = value`: {}", line_no, line))?;
+        let Some(key) = APPSTREAM_KEYS.iter().find(|k| **k == key) else {
+            return Err(format!(
+                "line {}: unsupported appstream key '{}' (supported: {})",
+                line_no,
+                key,
+                APPSTREAM_KEYS.join(", ")
+            )
+            .into());
+        };
+        let repeats = matches!(*key, "description" | "screenshot");
+        if !repeats && fields.iter().any(|(k, _)| k == key) {
+            return Err(format!("line {}: appstream key '{}' is given twice", line_no, key).into());
+        }
+        fields.push((key, value.to_string()));
+    }
+    let field = |key: &str| {
+        fields
+            .iter()
+            .find(|(k, _)| *k == key)
+            .map(|(_, value)| value.clone())
+    };
+    let all = |key: &str| -> Vec<&String> {
+        fields
+            .iter()
+            .filter(|(k, _)| *k == key)
+            .map(|(_, value)| value)
+            .collect()
+    };
+
+    let id = field("id").ok_or("The appstream block requires an id")?;
+    check_id(&id)?;
+    let component_type = field("type").unwrap_or("desktop-application".to_string());
+    if !["desktop-application", "console-application"].contains(&component_type.as_str()) {
+        return Err(format!(
+            "appstream type must be desktop-application or console-application, not '{}'",
+            component_type
+        )
+        .into());
+    }
+    let metadata_license = field("metadata_license").unwrap_or("CC0-1.0".to_string());
+    if !METADATA_LICENSES.contains(&metadata_license.as_str()) {
+        return Err(format!(
+            "appstream metadata_license must be one of {}: {}",
+            METADATA_LICENSES.join(", "),
+            metadata_license
+        )
+        .into());
+    }
+    let summary = field("summary").unwrap_or(metadata.description.clone());
+    if summary.ends_with('.') {
+        return Err(format!("appstream summary must not end with '.': {}", summary).into());
+    }
+    let mut paragraphs = all("description");
+    if paragraphs.is_empty() {
+        paragraphs.push(&metadata.description);
+    }
+
+    let mut xml = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    xml.push_str(&format!("<component type=\"{}\">\n", component_type));
+    xml.push_str(&format!("  <id>{}</id>\n", escape(&id)));
+    xml.push_str(&format!(
+        "  <name>{}</name>\n",
+        escape(&field("name").unwrap_or(metadata.name.clone()))
+    ));
+    xml.push_str(&format!("  <summary>{}</summary>\n", escape(&summary)));
+    xml.push_str(&format!(
+        "  <metadata_license>{}</metadata_license>\n",
+        metadata_license
+    ));
+    xml.push_str(&format!(
+        "  <project_license>{}</project_license>\n",
+        escape(&metadata.license)
+    ));
+    xml.push_str(&format!(
+        "  <developer id=\"{}\">\n    <name>{}</name>\n  </developer>\n",
+        escape(id.rsplit_once('.').map(|(prefix, _)| prefix).unwrap_or(&id)),
+        escape(&metadata.maintainer)
+    ));
+    xml.push_str("  <description>\n");
+    for paragraph in paragraphs {
+        xml.push_str(&format!("    <p>{}</p>\n", escape(paragraph)));
+    }
+    xml.push_str("  </description>\n");
+    if !metadata.url.is_empty() {
+        xml.push_str(&format!(
+            "  <url type=\"homepage\">{}</url>\n",
+            escape(&metadata.url)
+        ));
+    }
+
+    // No content descriptors means the application is suitable for all ages
+    xml.push_str("  <content_rating type=\"oars-1.1\"/>\n");
+
+    // Desktop applications are launched through the desktop entry, which defaults to the one
+    // generated from the desktop block
+    if component_type == "desktop-application" {
+        let launchable = match field("launchable") {
+            Some(launchable) => launchable,
+            None if !metadata.desktop.is_empty() => desktop::file_name(metadata),
+            None => {
+                return Err(
+                    "appstream launchable is required for a desktop-application \
+                            without a desktop block"
+                        .into(),
+                );
+            }
+        };
+        xml.push_str(&format!(
+            "  <launchable type=\"desktop-id\">{}</launchable>\n",
+            escape(&launchable)
+        ));
+    }
+    if component_type == "console-application" && !metadata.appimage_exec.is_empty() {
+        let binary = Path::new(&metadata.appimage_exec)
+            .file_name()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .into_owned();
+        xml.push_str(&format!(
+            "  <provides>\n    <binary>{}</binary>\n  </provides>\n",
+            escape(&binary)
+        ));
+    }
+
+    // `URL [caption]`; the first screenshot is the default one
+    let screenshots = all("screenshot");
+    if !screenshots.is_empty() {
+        xml.push_str("  <screenshots>\n");
+        for (index, screenshot) in screenshots.iter().enumerate() {
+            let (url, caption) = screenshot
+                .split_once(char::is_whitespace)
+                .map(|(url, caption)| (url, Some(caption.trim())))
+                .unwrap_or((screenshot, None));
+            if !url.starts_with("https://") && !url.starts_with("http://") {
+                return Err(format!("appstream screenshot must be an http(s) URL: {}", url).into());
+            }
+            if index == 0 {
+                xml.push_str("    <screenshot type=\"default\">\n");
+            } else {
+                xml.push_str("    <screenshot>\n");
+            }
+            if let Some(caption) = caption {
+                xml.push_str(&format!("      <caption>{}</caption>\n", escape(caption)));
+            }
+            xml.push_str(&format!("      <image>{}</image>\n", escape(url)));
+            xml.push_str("    </screenshot>\n");
+        }
+        xml.push_str("  </screenshots>\n");
+    }
+
+    if let Some(changelog) = field("changelog") {
+        let path = Path::new(&metadata.pkgbase).join(&changelog);
+        let contents = fs::read_to_string(&path)
+            .map_err(|e| format!("Failed to read changelog {:?}: {}", path, e))?;
+        let releases = parse_changelog(&contents).map_err(|e| format!("{}: {}", changelog, e))?;
+        if !releases.iter().any(|r| r.version == metadata.version) {
+            println!(
+                "Warning: changelog {} has no entry for version {}",
+                changelog, metadata.version
+            );
+        }
+        if !releases.is_empty() {
+            xml.push_str("  <releases>\n");
+            for release in &releases {
+                xml.push_str(&format!(
+                    "    <release version=\"{}\" date=\"{}\"",
+                    escape(&release.version),
+                    release.date
+                ));
+                if release.changes.is_empty() {
+                    xml.push_str("/>\n");
+                    continue;
+                }
+                xml.push_str(">\n      <description>\n        <ul>\n");
+                for change in &release.changes {
+                    xml.push_str(&format!("          <li>{}</li>\n", escape(change)));
+                }
+                xml.push_str("        </ul>\n      </description>\n    </release>\n");
+            }
+            xml.push_str("  </releases>\n");
+        }
+    }
+    xml.push_str("</component>\n");
+
+    validate(&xml, &id).map_err(|e| format!("Generated AppStream metainfo: {}", e))?;
+    Ok(Some((id, xml)))
+}
+
+/// Component IDs are reverse-DNS names such as `io.github.user.App`.
+fn check_id(id: &str) -> Result<(), String> {
+    let parts: Vec<&str> = id.split('.').collect();
+    let valid = parts.len() >= 3
+        && parts.iter().all(|part| {
+            !part.is_empty()
+                && !part.starts_with(|c: char| c.is_ascii_digit())
+                && part
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+        });
+    if !valid {
+        return Err(format!(
+            "appstream id must be a reverse-DNS name like io.github.user.App: {}",
+            id
+        ));
+    }
+    Ok(())
+}
+
+/// Parse a changelog in the Keep a Changelog layout into releases, newest first:
+///
+///   ## [1.1.0] - 2024-05-01
+///   ### Fixed
+///   - Reconnect after the adapter is unplugged
+///
+/// `[Unreleased]` is skipped and `###` subheadings only group the changes.
+fn parse_changelog(contents: &str) -> Result<Vec<Release>, String> {
+    let mut releases: Vec<Release> = Vec::new();
+    let mut in_release = false;
+    for (index, line) in contents.lines().enumerate() {
+        let line_no = index + 1;
+        let line = line.trim();
+        if let Some(heading) = line.strip_prefix("## ") {
+            let (version, date) = heading
+                .split_once(" - ")
+                .map(|(version, date)| (version.trim(), date.trim()))
+                .unwrap_or((heading.trim(), ""));
+            let version = version.trim_start_matches('[').trim_end_matches(']');
+            in_release = !version.eq_ignore_ascii_case("unreleased");
+            if !in_release {
+                continue;
+            }
+            if !is_date(date) {
+                return Err(format!(
+                    "line {}: expected `## [VERSION] - YYYY-MM-DD`: {}",
+                    line_no, line
+                ));
+            }
+            releases.push(Release {
+                version: version.to_string(),
+                date: date.to_string(),
+                changes: Vec::new(),
+            });
+        } else if let Some(change) = line.strip_prefix("- ").or(line.strip_prefix("* "))
+            && in_release
+            && let Some(release) = releases.last_mut()
+        {
+            release.changes.push(change.trim().to_string());
+        }
+    }
+    Ok(releases)
+}
+
+fn is_date(date: &str) -> bool {
+    let parts: Vec<&str> = date.split('-').collect();
+    matches!(parts.as_slice(), [year, month, day]
+        if year.len() == 4 && month.len() == 2 && day.len() == 2
+            && parts.iter().all(|p| p.chars().all(|c| c.is_ascii_digit())))
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Text of the first `<tag ...>...</tag>` element in `xml`.
+fn element<'a>(xml: &'a str, tag: &str) -> Option<&'a str> {
+    let open = format!("<{}", tag);
+    let start = xml
+        .match_indices(&open)
+        .map(|(index, _)| index + open.len())
+        .find(|&index| xml[index..].starts_with(['>', ' ']))?;
+    let content_start = start + xml[start..].find('>')? + 1;
+    let end = content_start + xml[content_start..].find(&format!("</{}>", tag))?;
+    Some(xml[content_start..end].trim())
+}
+
+/// Content of the first direct child `<tag>` of the element content `xml`, so that e.g. the
+/// `<name>` of a `<developer>` does not count as the component's name.
+fn child<'a>(xml: &'a str, tag: &str) -> Option<&'a str> {
+    let mut depth = 0usize;
+    let mut content_start = None;
+    let mut pos = 0;
+    while let Some(offset) = xml[pos..].find('<') {
+        let start = pos + offset;
+        if xml[start..].starts_with("<!--") {
+            pos = start + xml[start..].find("-->")? + 3;
+            continue;
+        }
+        let end = start + xml[start..].find('>')? + 1;
+        let inner = &xml[start + 1..end - 1];
+        pos = end;
+        if inner.starts_with(['?', '!']) {
+            continue;
+        }
+        if let Some(name) = inner.strip_prefix('/') {
+            depth = depth.checked_sub(1)?;
+            if let Some(content_start) = content_start.filter(|_| depth == 0) {
+                return (name.trim() == tag).then(|| xml[content_start..start].trim());
+            }
+            continue;
+        }
+        let name = inner
+            .split(|c: char| c.is_whitespace() || c == '/')
+            .next()
+            .unwrap_or_default();
+        let self_closing = inner.ends_with('/');
+        if depth == 0 && name == tag {
+            if self_closing {
+                return Some("");
+            }
+            content_start = Some(end);
+        }
+        if !self_closing {
+            depth += 1;
+        }
+    }
+    None
+}
+
+/// Check a metainfo file for the tags AppStream requires of every component: a `<component>`
+/// root holding a non-empty `id`, `name`, `summary`, `metadata_license`, `project_license`
+/// and `description`, and for desktop applications a `launchable`. The ID has to match the
+/// file name the metainfo is installed under.
+pub fn validate(xml: &str, expected_id: &str) -> Result<(), String> {
+    let component = element(xml, "component").ok_or("no <component> root")?;
+    for tag in REQUIRED_TAGS {
+        if child(component, tag).is_none_or(str::is_empty) {
+            return Err(format!("missing <{}>", tag));
+        }
+    }
+    let id = child(component, "id").unwrap_or_default();
+    if id != expected_id {
+        return Err(format!(
+            "<id>{}</id> does not match the file name {}",
+            id, expected_id
+        ));
+    }
+    if xml.contains("<component type=\"desktop-application\"")
+        && child(component, "launchable").is_none_or(str::is_empty)
+    {
+        return Err("desktop-application without <launchable>".to_string());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ID: &str = "io.github.twincan.TwinCAN";
+
+    fn metadata(appstream: &[&str], desktop: bool) -> Metadata {
+        Metadata {
+            name: "twincan".to_string(),
+            version: "1.1.0".to_string(),
+            description: "Browse CAN bus traffic".to_string(),
+            license: "GPL-3.0-or-later".to_string(),
+            maintainer: "Jane Doe".to_string(),
+            appstream: appstream
+                .iter()
+                .enumerate()
+                .map(|(index, line)| (index + 1, line.to_string()))
+                .collect(),
+            desktop: if desktop {
+                vec![(1, "Categories = Utility".to_string())]
+            } else {
+                Vec::new()
+            },
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn launchable_defaults_to_generated_desktop_entry() {
+        let id = format!("id = {}", ID);
+        let (_, xml) = generate(&metadata(&[&id], true)).unwrap().unwrap();
+        assert_eq!(element(&xml, "launchable"), Some("twincan.desktop"));
+
+        let error = generate(&metadata(&[&id], false)).unwrap_err().to_string();
+        assert!(error.contains("launchable is required"), "{}", error);
+
+        let (_, xml) = generate(&metadata(&[&id, "launchable = other.desktop"], false))
+            .unwrap()
+            .unwrap();
+        assert_eq!(element(&xml, "launchable"), Some("other.desktop"));
+
+        let (_, xml) = generate(&metadata(&[&id, "type = console-application"], false))
+            .unwrap()
+            .unwrap();
+        assert_eq!(element(&xml, "launchable"), None);
+    }
+
+    #[test]
+    fn generate_rejects_invalid_fields() {
+        for (line, message) in [
+            ("id = TwinCAN", "reverse-DNS name"),
+            ("type = web-application", "appstream type must be"),
+            (
+                "metadata_license = GPL-3.0",
+                "metadata_license must be one of",
+            ),
+            ("summary = Browse traffic.", "must not end with '.'"),
+            (
+                "screenshot = ftp://example.com/a.png",
+                "must be an http(s) URL",
+            ),
+            (
+                "homepage = https://example.com",
+                "unsupported appstream key",
+            ),
+            ("id", "expected `key = value`"),
+        ] {
+            let id = format!("id = {}", ID);
+            let lines = if line.starts_with("id ") {
+                vec![line]
+            } else {
+                vec![id.as_str(), line]
+            };
+            let error = generate(&metadata(&lines, true)).unwrap_err().to_string();
+            assert!(error.contains(message), "{}: {}", line, error);
+        }
+    }
+
+    #[test]
+    fn validate_checks_required_tags() {
+        let valid = format!(
+            "<component type=\"desktop-application\">\n\
+             <id>{}</id><name>TwinCAN</name><summary>Browse</summary>\n\
+             <metadata_license>CC0-1.0</metadata_license>\n\
+             <project_license>MIT</project_license>\n\
+             <description><p>Text</p></description>\n\
+             <launchable type=\"desktop-id\">twincan.desktop</launchable>\n\
+             </component>\n",
+            ID
+        );
+        validate(&valid, ID).unwrap();
+        let developer = "<developer id=\"org.example\"><name>Example</name></developer>\n";
+        let with_developer = valid.replacen("<name>", &format!("{}<name>", developer), 1);
+        validate(&with_developer, ID).unwrap();
+
+        for (from, to, message) in [
+            ("<name>TwinCAN</name>", "", "missing <name>"),
+            (
+                "<summary>Browse</summary>",
+                "<summary></summary>",
+                "missing <summary>",
+            ),
+            (
+                "<project_license>MIT</project_license>",
+                "",
+                "missing <project_license>",
+            ),
+            (
+                "twincan.desktop",
+                "",
+                "desktop-application without <launchable>",
+            ),
+            ("TwinCAN</id>", "Other</id>", "does not match the file name"),
+            ("<component type", "<components type", "no <component> root"),
+            // Fields of nested elements do not count for the component
+            (
+                "<name>TwinCAN</name>",
+                "<developer id=\"org.example\"><name>Example</name></developer>",
+                "missing <name>",
+            ),
+            (
+                "<summary>Browse</summary>",
+                "<!-- <summary>Browse</summary> -->",
+                "missing <summary>",
+            ),
+            (
+                "<project_license>MIT</project_license>",
+                "<releases><release><project_license>MIT</project_license></release></releases>",
+                "missing <project_license>",
+            ),
+        ] {
+            let error = validate(&valid.replacen(from, to, 1), ID).unwrap_err();
+            assert!(error.contains(message), "{}: {}", message, error);
+        }
+    }
+
+    #[test]
+    fn changelog_releases() {
+        let releases = parse_changelog(
+            "# Changelog\n\
+             ## [Unreleased]\n\
+             - Not yet\n\
+             ## [1.1.0] - 2024-05-01\n\
+             ### Fixed\n\
+             - Reconnect after unplugging\n\
+             * Faster startup\n\
+             ## 1.0.0 - 2024-01-15\n",
+        )
+        .unwrap();
+        let summary: Vec<(&str, &str, usize)> = releases
+            .iter()
+            .map(|r| (r.version.as_str(), r.date.as_str(), r.changes.len()))
+            .collect();
+        assert_eq!(
+            summary,
+            [("1.1.0", "2024-05-01", 2), ("1.0.0", "2024-01-15", 0)]
+        );
+
+        let error = parse_changelog("## [1.0.0] - May 2024\n").err().unwrap();
+        assert!(error.starts_with("line 1: expected"), "{}", error);
+    }
+}
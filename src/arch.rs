@@ -0,0 +1,101 @@
+use object::elf::{self, Machine};
+
+/// Names of one architecture in every supported package format.
+#[derive(Debug)]
+pub struct ArchNames {
+    /// Name used in the metadata `arch` list (follows ALPM/uname conventions)
+    pub canonical: &'static str,
+    pub deb: &'static str,
+    pub rpm: &'static str,
+    pub alpm: &'static str,
+    /// Name of the AppImage runtime; `None` where no runtime exists
+    pub appimage: Option<&'static str>,
+    /// Debian multiarch triplet, the library directory under `/usr/lib`
+    pub multiarch: &'static str,
+    /// ELF `e_machine` of binaries built for the architecture; `None` for `any`
+    pub elf_machine: Option<Machine>,
+}
+
+const ARCHES: &[ArchNames] = &[
+    ArchNames {
+        canonical: "x86_64",
+        deb: "amd64",
+        rpm: "x86_64",
+        alpm: "x86_64",
+        appimage: Some("x86_64"),
+        multiarch: "x86_64-linux-gnu",
+        elf_machine: Some(elf::EM_X86_64),
+    },
+    ArchNames {
+        canonical: "i686",
+        deb: "i386",
+        rpm: "i686",
+        alpm: "i686",
+        appimage: Some("i686"),
+        multiarch: "i386-linux-gnu",
+        elf_machine: Some(elf::EM_386),
+    },
+    ArchNames {
+        canonical: "aarch64",
+        deb: "arm64",
+        rpm: "aarch64",
+        alpm: "aarch64",
+        appimage: Some("aarch64"),
+        multiarch: "aarch64-linux-gnu",
+        elf_machine: Some(elf::EM_AARCH64),
+    },
+    ArchNames {
+        canonical: "armv7h",
+        deb: "armhf",
+        rpm: "armv7hl",
+        alpm: "armv7h",
+        appimage: Some("armhf"),
+        multiarch: "arm-linux-gnueabihf",
+        elf_machine: Some(elf::EM_ARM),
+    },
+    ArchNames {
+        canonical: "riscv64",
+        deb: "riscv64",
+        rpm: "riscv64",
+        alpm: "riscv64",
+        appimage: None,
+        multiarch: "riscv64-linux-gnu",
+        elf_machine: Some(elf::EM_RISCV),
+    },
+    ArchNames {
+        canonical: "ppc64le",
+        deb: "ppc64el",
+        rpm: "ppc64le",
+        alpm: "ppc64le",
+        appimage: None,
+        multiarch: "powerpc64le-linux-gnu",
+        elf_machine: Some(elf::EM_PPC64),
+    },
+    ArchNames {
+        canonical: "any",
+        deb: "all",
+        rpm: "noarch",
+        alpm: "any",
+        appimage: None,
+        multiarch: "",
+        elf_machine: None,
+    },
+];
+
+/// Look up an architecture by its canonical name, rejecting anything not in the table.
+pub fn lookup(arch: &str) -> Result<&'static ArchNames, Box<dyn std::error::Error>> {
+    ARCHES.iter().find(|a| a.canonical == arch).ok_or_else(|| {
+        let known: Vec<&str> = ARCHES.iter().map(|a| a.canonical).collect();
+        format!(
+            "Unknown architecture '{}' (supported: {})",
+            arch,
+            known.join(", ")
+        )
+        .into()
+    })
+}
+
+/// Look up the architecture whose binaries have ELF machine `machine`.
+pub fn by_machine(machine: Machine) -> Option<&'static ArchNames> {
+    ARCHES.iter().find(|a| a.elf_machine == Some(machine))
+}
@@ -0,0 +1,364 @@
+use crate::metadata::Metadata;
+use crate::reproducible;
+use crate::systemd;
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use tar::{Builder, EntryType, Header};
+
+/// Ownership and permissions of a staged path as they should appear in the package,
+/// independent of the user running the build.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileAttributes {
+    pub mode: u32,
+    pub user: String,
+    pub uid: u32,
+    pub group: String,
+    pub gid: u32,
+}
+
+impl FileAttributes {
+    pub fn root(mode: u32) -> Self {
+        Self {
+            mode,
+            user: "root".to_string(),
+            uid: 0,
+            group: "root".to_string(),
+            gid: 0,
+        }
+    }
+}
+
+/// Fakeroot-style attribute table filled in by the package DSL, keyed by the path relative to
+/// the staging directory (e.g. `usr/bin/twincan`). Paths without an entry are owned by
+/// `root:root` with normalized permissions, see [`AttributeTable::get`].
+#[derive(Debug, Default)]
+pub struct AttributeTable {
+    entries: BTreeMap<PathBuf, FileAttributes>,
+}
+
+impl AttributeTable {
+    pub fn insert(&mut self, rel_path: impl Into<PathBuf>, attributes: FileAttributes) {
+        self.entries.insert(rel_path.into(), attributes);
+    }
+
+    /// Whether the DSL recorded attributes for `rel_path` (e.g. a directory from `mkdir`).
+    pub fn contains(&self, rel_path: &Path) -> bool {
+        self.entries.contains_key(rel_path)
+    }
+
+    /// Attributes for `rel_path`, falling back to root ownership. Unrecorded modes are
+    /// normalized so they do not depend on the umask of the build: 0755 for directories and
+    /// executables, 0644 for other files and 0777 for symlinks.
+    pub fn get(&self, base_dir: &Path, rel_path: &Path) -> io::Result<FileAttributes> {
+        if let Some(attributes) = self.entries.get(rel_path) {
+            return Ok(attributes.clone());
+        }
+        let metadata = fs::symlink_metadata(base_dir.join(rel_path))?;
+        let file_type = metadata.file_type();
+        let mode = if file_type.is_symlink() {
+            0o777
+        } else if file_type.is_dir() || metadata.permissions().mode() & 0o111 != 0 {
+            0o755
+        } else {
+            0o644
+        };
+        Ok(FileAttributes::root(mode))
+    }
+
+    /// Build a tar header for a staged path carrying the recorded ownership and mode.
+    pub fn tar_header(&self, base_dir: &Path, rel_path: &Path) -> io::Result<Header> {
+        let abs_path = base_dir.join(rel_path);
+        let metadata = fs::symlink_metadata(&abs_path)?;
+        let attributes = self.get(base_dir, rel_path)?;
+
+        let mut header = Header::new_gnu();
+        header.set_metadata(&metadata);
+        header.set_mtime(reproducible::clamp_mtime(header.mtime()?));
+        header.set_mode(attributes.mode);
+        header.set_uid(attributes.uid as u64);
+        header.set_gid(attributes.gid as u64);
+        header.set_username(&attributes.user)?;
+        header.set_groupname(&attributes.group)?;
+        if metadata.file_type().is_symlink() {
+            header.set_entry_type(EntryType::Symlink);
+            header.set_size(0);
+        }
+        Ok(header)
+    }
+
+    /// Append a staged file, directory or symlink to `tar` using [`Self::tar_header`].
+    pub fn append_to_tar<W: Write>(
+        &self,
+        tar: &mut Builder<W>,
+        base_dir: &Path,
+        rel_path: &Path,
+    ) -> io::Result<()> {
+        let abs_path = base_dir.join(rel_path);
+        let mut header = self.tar_header(base_dir, rel_path)?;
+        let file_type = fs::symlink_metadata(&abs_path)?.file_type();
+
+        if file_type.is_symlink() {
+            tar.append_link(&mut header, rel_path, fs::read_link(&abs_path)?)
+        } else if file_type.is_dir() {
+            tar.append_data(&mut header, rel_path, io::empty())
+        } else {
+            tar.append_data(&mut header, rel_path, File::open(&abs_path)?)
+        }
+    }
+}
+
+/// Users and groups every distribution's base system provides. Their ids differ between
+/// distributions, so like dynamically allocated ids they are recorded as 0 and package
+/// managers map them by name at install time.
+const SYSTEM_NAMES: &[&str] = &[
+    "root",
+    "bin",
+    "daemon",
+    "sys",
+    "adm",
+    "tty",
+    "disk",
+    "lp",
+    "mail",
+    "news",
+    "uucp",
+    "man",
+    "proxy",
+    "kmem",
+    "dialout",
+    "cdrom",
+    "floppy",
+    "tape",
+    "wheel",
+    "sudo",
+    "audio",
+    "video",
+    "input",
+    "kvm",
+    "render",
+    "dip",
+    "www-data",
+    "http",
+    "backup",
+    "operator",
+    "list",
+    "irc",
+    "shadow",
+    "utmp",
+    "lock",
+    "mem",
+    "log",
+    "plugdev",
+    "staff",
+    "games",
+    "users",
+    "ftp",
+    "systemd-journal",
+    "nobody",
+    "nogroup",
+];
+
+/// Owners and groups known at build time: the users and groups the `users:` block declares,
+/// with their fixed id if any, and the system names of [`SYSTEM_NAMES`]. The build host's
+/// databases are never consulted, since its ids have nothing to do with the system the
+/// package is installed on.
+#[derive(Debug, Default)]
+pub struct Owners {
+    users: Vec<(String, Option<u32>)>,
+    groups: Vec<(String, Option<u32>)>,
+    /// Primary group of each declared user
+    primary_groups: Vec<(String, String)>,
+}
+
+impl Owners {
+    /// Collect the users and groups from the sysusers.d lines generated for the `users:` block.
+    pub fn from_metadata(metadata: &Metadata) -> Result<Self, Box<dyn std::error::Error>> {
+        let (sysusers, _) = systemd::users_config(metadata)?;
+        let mut owners = Self::default();
+        for line in sysusers.lines() {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            match fields.as_slice() {
+                // `u NAME UID:GROUP` joins an existing group, otherwise a group of the same
+                // name is created with the UID or the GID of `UID:GID`
+                ["u", name, id, ..] => {
+                    let (uid, gid) = id.split_once(':').unwrap_or((id, id));
+                    owners.users.push((name.to_string(), uid.parse().ok()));
+                    let group = match gid.parse() {
+                        Err(_) if gid != "-" => gid.to_string(),
+                        gid => {
+                            owners.groups.push((name.to_string(), gid.ok()));
+                            name.to_string()
+                        }
+                    };
+                    owners.primary_groups.push((name.to_string(), group));
+                }
+                ["g", name, gid, ..] => {
+                    owners.groups.push((name.to_string(), gid.parse().ok()));
+                }
+                _ => {}
+            }
+        }
+        Ok(owners)
+    }
+
+    /// Resolve an `-o` owner given as a name or numeric uid.
+    pub fn user(&self, spec: &str) -> Result<(String, u32), Box<dyn std::error::Error>> {
+        resolve_id(spec, &self.users, "user")
+    }
+
+    /// Resolve a `-g` group given as a name or numeric gid.
+    pub fn group(&self, spec: &str) -> Result<(String, u32), Box<dyn std::error::Error>> {
+        resolve_id(spec, &self.groups, "group")
+    }
+
+    /// The primary group of `user`: the one sysusers creates or assigns for a declared user,
+    /// otherwise the group of the same name, as for system users like `games`.
+    pub fn primary_group<'a>(&'a self, user: &'a str) -> &'a str {
+        self.primary_groups
+            .iter()
+            .find(|(name, _)| name == user)
+            .map_or(user, |(_, group)| group)
+    }
+}
+
+/// Names are kept as written so package managers map them at install time; the id is the
+/// declared one, or 0 for dynamically allocated ids and system names. A numeric id keeps its
+/// declared name. Any other name is an error, since it would silently become root.
+fn resolve_id(
+    spec: &str,
+    declared: &[(String, Option<u32>)],
+    kind: &str,
+) -> Result<(String, u32), Box<dyn std::error::Error>> {
+    if spec == "root" || spec == "0" {
+        return Ok(("root".to_string(), 0));
+    }
+    if let Ok(id) = spec.parse::<u32>() {
+        let name = declared
+            .iter()
+            .find(|(_, declared_id)| *declared_id == Some(id))
+            .map(|(name, _)| name.clone())
+            .unwrap_or_else(|| spec.to_string());
+        return Ok((name, id));
+    }
+    match declared.iter().find(|(name, _)| name == spec) {
+        Some((_, id)) => Ok((spec.to_string(), id.unwrap_or(0))),
+        None if SYSTEM_NAMES.contains(&spec) => Ok((spec.to_string(), 0)),
+        None => Err(format!("Unknown {} '{}': declare it in the users block", kind, spec).into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn owners() -> Owners {
+        let users = [
+            "user twincan -u 950 -c \"TwinCAN daemon\"",
+            "user helper",
+            "user worker -u 960:twincan",
+            "user builder -u 970:971",
+            "group scanners -g 980",
+            "group spool",
+        ];
+        let metadata = Metadata {
+            users: users
+                .iter()
+                .enumerate()
+                .map(|(index, line)| (index + 1, line.to_string()))
+                .collect(),
+            ..Default::default()
+        };
+        Owners::from_metadata(&metadata).unwrap()
+    }
+
+    #[test]
+    fn unrecorded_modes_are_normalized() {
+        let base_dir = std::env::temp_dir().join(format!("attributes-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&base_dir);
+        fs::create_dir_all(base_dir.join("private")).unwrap();
+        for (name, mode) in [("data", 0o600), ("tool", 0o700), ("shared", 0o664)] {
+            fs::write(base_dir.join(name), name).unwrap();
+            fs::set_permissions(base_dir.join(name), fs::Permissions::from_mode(mode)).unwrap();
+        }
+        fs::set_permissions(base_dir.join("private"), fs::Permissions::from_mode(0o700)).unwrap();
+        std::os::unix::fs::symlink("data", base_dir.join("link")).unwrap();
+
+        let mut table = AttributeTable::default();
+        table.insert("recorded", FileAttributes::root(0o4750));
+        fs::write(base_dir.join("recorded"), "recorded").unwrap();
+
+        for (name, mode) in [
+            ("private", 0o755),
+            ("data", 0o644),
+            ("tool", 0o755),
+            ("shared", 0o644),
+            ("link", 0o777),
+            ("recorded", 0o4750),
+        ] {
+            let attributes = table.get(&base_dir, Path::new(name)).unwrap();
+            assert_eq!(attributes, FileAttributes::root(mode), "{}", name);
+        }
+        fs::remove_dir_all(&base_dir).unwrap();
+    }
+
+    #[test]
+    fn resolve_declared_and_system_names() {
+        let owners = owners();
+        for (spec, expected) in [
+            ("root", ("root", 0)),
+            ("0", ("root", 0)),
+            ("twincan", ("twincan", 950)),
+            ("950", ("twincan", 950)),
+            ("helper", ("helper", 0)),
+            ("worker", ("worker", 960)),
+            ("builder", ("builder", 970)),
+            ("games", ("games", 0)),
+            ("1234", ("1234", 1234)),
+        ] {
+            let resolved = owners.user(spec).unwrap();
+            assert_eq!(resolved, (expected.0.to_string(), expected.1), "{}", spec);
+        }
+        for (spec, expected) in [
+            ("twincan", ("twincan", 950)),
+            ("helper", ("helper", 0)),
+            ("builder", ("builder", 971)),
+            ("971", ("builder", 971)),
+            ("scanners", ("scanners", 980)),
+            ("spool", ("spool", 0)),
+            ("nogroup", ("nogroup", 0)),
+        ] {
+            let resolved = owners.group(spec).unwrap();
+            assert_eq!(resolved, (expected.0.to_string(), expected.1), "{}", spec);
+        }
+    }
+
+    #[test]
+    fn reject_undeclared_names() {
+        let owners = owners();
+        let error = owners.user("twincann").unwrap_err().to_string();
+        assert_eq!(
+            error,
+            "Unknown user 'twincann': declare it in the users block"
+        );
+        // `group` declares no user and `worker` joins twincan instead of creating a group
+        assert!(owners.user("scanners").is_err());
+        assert!(owners.group("worker").is_err());
+    }
+
+    #[test]
+    fn primary_groups() {
+        let owners = owners();
+        for (user, group) in [
+            ("twincan", "twincan"),
+            ("helper", "helper"),
+            ("worker", "twincan"),
+            ("builder", "builder"),
+            ("games", "games"),
+        ] {
+            assert_eq!(owners.primary_group(user), group, "{}", user);
+        }
+    }
+}
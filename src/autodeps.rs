@@ -0,0 +1,304 @@
+use crate::elf;
+use crate::metadata::Metadata;
+use crate::version::VersionFormat;
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+/// Shared libraries linked and provided by the ELF files staged for a package.
+#[derive(Debug, Default)]
+pub struct Libraries {
+    /// Sonames needed from other packages, with whether the linking file is 64-bit
+    pub needed: Vec<(String, bool)>,
+    /// Sonames of the staged shared libraries, with whether the library is 64-bit
+    pub provided: Vec<(String, bool)>,
+}
+
+/// Collect `DT_NEEDED` and `DT_SONAME` from the ELF files staged in `pkg_dir`. Libraries the
+/// package ships itself are not listed as needed.
+pub fn scan(pkg_dir: &str) -> Result<Libraries, Box<dyn std::error::Error>> {
+    let base_dir = Path::new(pkg_dir);
+    let mut libraries = Libraries::default();
+    let mut file_names = Vec::new();
+
+    for rel_path in elf::staged_elf_files(base_dir)? {
+        let info = elf::dynamic_info(&fs::read(base_dir.join(&rel_path))?)
+            .map_err(|e| format!("/{}: {}", rel_path.display(), e))?;
+        let Some(info) = info else { continue };
+        for soname in info.needed {
+            if !libraries.needed.contains(&(soname.clone(), info.is_64)) {
+                libraries.needed.push((soname, info.is_64));
+            }
+        }
+        if let Some(soname) = info.soname {
+            libraries.provided.push((soname, info.is_64));
+        }
+        if let Some(name) = rel_path.file_name() {
+            file_names.push(name.to_string_lossy().into_owned());
+        }
+    }
+
+    libraries.needed.retain(|(soname, _)| {
+        !file_names.contains(soname) && !libraries.provided.iter().any(|(p, _)| p == soname)
+    });
+    libraries.needed.sort();
+    libraries.provided.sort();
+    Ok(libraries)
+}
+
+/// RPM capability for a soname, e.g. `libfoo.so.1()(64bit)` as generated by rpm's elfdeps.
+pub fn rpm_capability(soname: &str, is_64: bool) -> String {
+    if is_64 {
+        format!("{}()(64bit)", soname)
+    } else {
+        soname.to_string()
+    }
+}
+
+/// Package name of a dependency, without a version constraint (`foo>=1`, `foo (>= 1)`).
+fn package_name(depend: &str) -> &str {
+    depend
+        .split(|c: char| c.is_whitespace() || "<>=(".contains(c))
+        .next()
+        .unwrap_or(depend)
+}
+
+/// ALPM provision for a soname, e.g. `libfoo.so=1-64` as generated by makepkg. `None` for a
+/// soname without a version.
+pub fn alpm_provision(soname: &str, is_64: bool) -> Option<String> {
+    let (name, version) = split_soname(soname)?;
+    Some(format!(
+        "{}={}-{}",
+        name,
+        version,
+        if is_64 { 64 } else { 32 }
+    ))
+}
+
+/// Split a soname into library name and version: `libfoo.so.1` into `libfoo.so` and `1`.
+fn split_soname(soname: &str) -> Option<(&str, &str)> {
+    let index = soname.find(".so.")?;
+    Some((&soname[..index + 3], &soname[index + 4..]))
+}
+
+/// deb `shlibs` line telling dpkg-shlibdeps which package provides a soname, e.g.
+/// `libfoo 1 foo (>= 1.0)`. `None` for a soname dpkg cannot parse.
+pub fn deb_shlibs_line(soname: &str, metadata: &Metadata) -> Option<String> {
+    let (library, version) = match split_soname(soname) {
+        Some((name, version)) => (name.trim_end_matches(".so"), version),
+        // libfoo-1.2.so
+        None => soname.strip_suffix(".so")?.rsplit_once('-')?,
+    };
+    let upstream = if metadata.epoch.is_empty() {
+        metadata.version.clone()
+    } else {
+        format!("{}:{}", metadata.epoch, metadata.version)
+    };
+    Some(format!(
+        "{} {} {} (>= {})",
+        library, version, metadata.name, upstream
+    ))
+}
+
+/// Add the shared libraries the binaries staged in `pkg_dir` provide to the relations of
+/// `format`, as RPM soname capabilities and ALPM soname provides. With the `autodeps` option,
+/// the libraries they link against are added as dependencies too: soname capabilities for RPM,
+/// and for deb and ALPM the packages the `soname_map` file resolves the sonames to, since
+/// package names differ between distributions and releases.
+pub fn with_library_relations(
+    metadata: &Metadata,
+    pkg_dir: &str,
+    format: VersionFormat,
+) -> Result<Metadata, Box<dyn std::error::Error>> {
+    let mut metadata = metadata.clone();
+    // deb has no soname provides, so there is nothing to do without autodeps
+    if format == VersionFormat::Deb && !metadata.has_option("autodeps") {
+        return Ok(metadata);
+    }
+    add_library_relations(&mut metadata, &scan(pkg_dir)?, format)?;
+    Ok(metadata)
+}
+
+/// Add the relations of [`with_library_relations`] for the scanned `libraries`.
+fn add_library_relations(
+    metadata: &mut Metadata,
+    libraries: &Libraries,
+    format: VersionFormat,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let autodeps = metadata.has_option("autodeps");
+
+    match format {
+        VersionFormat::Rpm => {
+            if autodeps {
+                for (soname, is_64) in &libraries.needed {
+                    let capability = rpm_capability(soname, *is_64);
+                    if !metadata.rpm_depends.contains(&capability) {
+                        metadata.rpm_depends.push(capability);
+                    }
+                }
+            }
+            for (soname, is_64) in &libraries.provided {
+                let capability = rpm_capability(soname, *is_64);
+                if !metadata.provides.contains(&capability) {
+                    println!("Detected RPM provides {}", capability);
+                    metadata.provides.push(capability);
+                }
+            }
+        }
+        VersionFormat::Alpm => {
+            for (soname, is_64) in &libraries.provided {
+                let Some(provision) = alpm_provision(soname, *is_64) else {
+                    continue;
+                };
+                if !metadata.provides.contains(&provision) {
+                    println!("Detected ALPM provides {}", provision);
+                    metadata.provides.push(provision);
+                }
+            }
+            if autodeps {
+                add_package_depends(metadata, libraries, format)?;
+            }
+        }
+        VersionFormat::Deb => {
+            if autodeps {
+                add_package_depends(metadata, libraries, format)?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Add the deb or ALPM packages providing the needed sonames to the depends, and report
+/// declared dependencies no binary links against.
+fn add_package_depends(
+    metadata: &mut Metadata,
+    libraries: &Libraries,
+    format: VersionFormat,
+) -> Result<(), Box<dyn std::error::Error>> {
+    if libraries.needed.is_empty() {
+        return Ok(());
+    }
+
+    let map = soname_map(metadata)?;
+    let (format_name, depends) = match format {
+        VersionFormat::Deb => ("deb", &mut metadata.deb_depends),
+        _ => ("ALPM", &mut metadata.alpm_depends),
+    };
+    let declared = depends.clone();
+    let mut used = Vec::new();
+    for (soname, _) in &libraries.needed {
+        let Some((alpm, deb)) = map.get(soname.as_str()) else {
+            println!(
+                "Warning: no {} package known for {}; add it to soname_map",
+                format_name, soname
+            );
+            continue;
+        };
+        let package = if format == VersionFormat::Deb {
+            deb
+        } else {
+            alpm
+        };
+        // `-` marks a soname that needs no dependency
+        if package == "-" {
+            continue;
+        }
+        if !depends.iter().any(|d| package_name(d) == package) {
+            println!(
+                "Detected {} dependency {} ({})",
+                format_name, package, soname
+            );
+            depends.push(package.clone());
+        }
+        used.push(package.clone());
+    }
+    for depend in declared {
+        if !used.iter().any(|package| package == package_name(&depend)) {
+            println!(
+                "Warning: {} dependency '{}' is not linked by any staged binary",
+                format_name, depend
+            );
+        }
+    }
+
+    Ok(())
+}
+
+/// Soname to (ALPM package, deb package) map read from the `soname_map` file, which has one
+/// `SONAME ALPM_PACKAGE DEB_PACKAGE` line per soname.
+fn soname_map(
+    metadata: &Metadata,
+) -> Result<HashMap<String, (String, String)>, Box<dyn std::error::Error>> {
+    let mut map = HashMap::new();
+    if metadata.soname_map.is_empty() {
+        return Ok(map);
+    }
+
+    let contents = fs::read_to_string(&metadata.soname_map)
+        .map_err(|e| format!("Failed to read soname_map {}: {}", metadata.soname_map, e))?;
+    for (index, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        match line.split_whitespace().collect::<Vec<_>>().as_slice() {
+            [soname, alpm, deb] => {
+                map.insert(soname.to_string(), (alpm.to_string(), deb.to_string()));
+            }
+            _ => {
+                return Err(format!(
+                    "{}:{}: expected `SONAME ALPM_PACKAGE DEB_PACKAGE`: {}",
+                    metadata.soname_map,
+                    index + 1,
+                    line
+                )
+                .into());
+            }
+        }
+    }
+    Ok(map)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn libraries() -> Libraries {
+        Libraries {
+            needed: vec![("libc.so.6".to_string(), true)],
+            provided: vec![("libfoo.so.1".to_string(), true)],
+        }
+    }
+
+    #[test]
+    fn soname_provides_without_autodeps() {
+        let metadata = Metadata {
+            options: vec!["strip".to_string()],
+            ..Default::default()
+        };
+        for (format, provides) in [
+            (VersionFormat::Rpm, &["libfoo.so.1()(64bit)"][..]),
+            (VersionFormat::Alpm, &["libfoo.so=1-64"]),
+            (VersionFormat::Deb, &[]),
+        ] {
+            let mut metadata = metadata.clone();
+            add_library_relations(&mut metadata, &libraries(), format).unwrap();
+            assert_eq!(metadata.provides, provides, "{:?}", format);
+            assert!(metadata.rpm_depends.is_empty(), "{:?}", format);
+            assert!(metadata.alpm_depends.is_empty(), "{:?}", format);
+            assert!(metadata.deb_depends.is_empty(), "{:?}", format);
+        }
+    }
+
+    #[test]
+    fn autodeps_adds_needed_libraries() {
+        let mut metadata = Metadata {
+            options: vec!["autodeps".to_string()],
+            ..Default::default()
+        };
+        add_library_relations(&mut metadata, &libraries(), VersionFormat::Rpm).unwrap();
+        assert_eq!(metadata.rpm_depends, ["libc.so.6()(64bit)"]);
+        assert_eq!(metadata.provides, ["libfoo.so.1()(64bit)"]);
+    }
+}
@@ -1,48 +1,190 @@
 use super::build_info::create_build_info;
+use super::mtree::create_mtree;
 use super::pkg_info::create_package_info;
+use crate::arch;
+use crate::attributes::AttributeTable;
+use crate::autodeps;
 use crate::chmod::chmod_package;
-use crate::metadata;
+use crate::compression::{self, Algorithm, Compression};
+use crate::debug;
+use crate::metadata::{self, Metadata};
+use crate::scripts;
+use crate::strip;
+use crate::version::{self, VersionFormat};
 
-use alpm_compress::compression::CompressionSettings;
-use alpm_mtree::create_mtree_v2_from_input_dir;
-use alpm_package::{InputDir, OutputDir, Package, PackageCreationConfig, PackageInput};
-use std::path::PathBuf;
+use alpm_compress::compression::{CompressionSettings, ZstdThreads};
+use alpm_compress::tarball::TarballBuilder;
+use alpm_types::{CompressionAlgorithmFileExtension, MetadataFileName};
+use std::fs::File;
+use std::path::{Path, PathBuf};
 
-pub fn alpm_build() -> Result<(), Box<dyn std::error::Error>> {
-    let metadata = metadata::extract_metadata("metadata")?;
-    let alpm_dir = format!("{}.alpm", metadata.name);
-    let base_dir = PathBuf::from(&alpm_dir);
-    chmod_package(&alpm_dir, false)?;
-    let input_path = std::env::current_dir()?.join(&base_dir);
-    let input_dir = InputDir::new(input_path)?;
+pub fn alpm_build(arch: &str, compression: Option<&str>) -> Result<(), Box<dyn std::error::Error>> {
+    let metadata = metadata::extract_metadata_for_arch("metadata", Some(arch))?;
+    version::validate(VersionFormat::Alpm, &metadata)?;
+
+    // makepkg defaults to zstd
+    let compression = compression_settings(compression::resolve(
+        "ALPM",
+        compression,
+        &metadata.alpm_compression,
+        Compression::new(Algorithm::Zstd, None),
+        &[
+            Algorithm::Zstd,
+            Algorithm::Xz,
+            Algorithm::Gzip,
+            Algorithm::Bzip2,
+            Algorithm::None,
+        ],
+    )?)?;
 
     // Use a permanent output directory in the current working directory
     let output_path = std::env::current_dir()?.join("output");
     if !output_path.exists() {
         std::fs::create_dir_all(&output_path)?;
     }
-    let output_dir = OutputDir::new(output_path)?;
+
+    for package in metadata.split_packages() {
+        build_package(package, &output_path, &compression)?;
+    }
+    Ok(())
+}
+
+/// Stage and archive one (possibly split) package.
+fn build_package(
+    metadata: &Metadata,
+    output_path: &Path,
+    compression: &CompressionSettings,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let alpm_dir = format!("{}.alpm", metadata.name);
+    let attributes = chmod_package(&alpm_dir, metadata, false)?;
+    let debug_package = debug::split_debug_info(metadata, &alpm_dir, "-debug", ".alpm")?;
+    strip::strip_package(&alpm_dir, metadata)?;
+    let metadata = &autodeps::with_library_relations(metadata, &alpm_dir, VersionFormat::Alpm)?;
+
+    write_package(metadata, &alpm_dir, &attributes, output_path, compression)?;
+    if let Some(debug) = debug_package {
+        write_package(
+            &debug.metadata,
+            &debug.pkg_dir,
+            &debug.attributes,
+            output_path,
+            compression,
+        )?;
+    }
+    Ok(())
+}
+
+/// Add the metadata files to a staged package and archive it.
+fn write_package(
+    metadata: &Metadata,
+    alpm_dir: &str,
+    attributes: &AttributeTable,
+    output_path: &Path,
+    compression: &CompressionSettings,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let base_dir = PathBuf::from(alpm_dir);
 
     // Create a valid, but minimal BUILDINFOv1 file.
-    create_build_info(&input_dir)?;
+    create_build_info(&base_dir, metadata)?;
 
     // Create a valid, but minimal PKGINFOv1 file.
-    create_package_info(&input_dir)?;
+    create_package_info(&base_dir, metadata)?;
+
+    // Create the .INSTALL scriptlet before the MTREE so that it is listed there.
+    if let Some(script) = scripts::install_functions(&[
+        ("pre_install", &metadata.pre_install),
+        ("post_install", &metadata.post_install),
+        ("pre_upgrade", &metadata.pre_upgrade),
+        ("post_upgrade", &metadata.post_upgrade),
+        ("pre_remove", &metadata.pre_remove),
+        ("post_remove", &metadata.post_remove),
+    ]) {
+        std::fs::write(base_dir.join(".INSTALL"), script)?;
+    }
 
-    // Create a valid ALPM-MTREEv1 file from the input directory.
-    create_mtree_v2_from_input_dir(&input_dir)?;
+    // Create a valid ALPM-MTREEv2 file carrying the owners and modes recorded by the DSL.
+    create_mtree(&base_dir, attributes)?;
 
-    // Create PackageInput and PackageCreationConfig.
-    let package_input: PackageInput = input_dir.try_into()?;
-    let config =
-        PackageCreationConfig::new(package_input, output_dir, CompressionSettings::default())?;
-    // Create package file.
-    Package::try_from(&config)?;
+    // Create package file. The archive is written directly rather than through alpm-package,
+    // which would take owners from disk and require building as root.
+    let extension = Option::<CompressionAlgorithmFileExtension>::from(compression)
+        .map(|ext| format!(".{}", ext))
+        .unwrap_or_default();
+    let package_path = output_path.join(format!(
+        "{}-{}-{}.pkg.tar{}",
+        metadata.name,
+        version::full_version(metadata),
+        arch::lookup(&metadata.target_arch)?.alpm,
+        extension
+    ));
+
+    let mut builder = TarballBuilder::new(File::create(&package_path)?, compression)?;
+    for rel_path in package_entries(&base_dir)? {
+        attributes.append_to_tar(builder.inner_mut(), &base_dir, &rel_path)?;
+    }
+    builder.finish()?;
 
     // Cleanup
     //std::fs::remove_dir_all(&base_dir)?;
 
     println!("Package created successfully!");
-    println!("Location: {}/output/", std::env::current_dir()?.display());
+    println!("Location: {}", package_path.display());
     Ok(())
 }
+
+fn compression_settings(
+    compression: Compression,
+) -> Result<CompressionSettings, Box<dyn std::error::Error>> {
+    let level = compression.level;
+    Ok(match compression.algorithm {
+        Algorithm::Zstd => CompressionSettings::Zstd {
+            compression_level: level
+                .map(TryInto::try_into)
+                .transpose()?
+                .unwrap_or_default(),
+            threads: ZstdThreads::default(),
+        },
+        Algorithm::Xz => CompressionSettings::Xz {
+            compression_level: level
+                .map(TryInto::try_into)
+                .transpose()?
+                .unwrap_or_default(),
+        },
+        Algorithm::Gzip => CompressionSettings::Gzip {
+            compression_level: level
+                .map(TryInto::try_into)
+                .transpose()?
+                .unwrap_or_default(),
+        },
+        Algorithm::Bzip2 => CompressionSettings::Bzip2 {
+            compression_level: level
+                .map(TryInto::try_into)
+                .transpose()?
+                .unwrap_or_default(),
+        },
+        Algorithm::None | Algorithm::Lz4 => CompressionSettings::None,
+    })
+}
+
+/// Paths to archive, metadata files first as pacman expects, then the payload in sorted order.
+fn package_entries(base_dir: &Path) -> Result<Vec<PathBuf>, Box<dyn std::error::Error>> {
+    let metadata_files = [
+        MetadataFileName::BuildInfo,
+        MetadataFileName::Mtree,
+        MetadataFileName::PackageInfo,
+    ]
+    .map(|name| PathBuf::from(name.as_ref()));
+
+    let mut entries = metadata_files.to_vec();
+    for entry in walkdir::WalkDir::new(base_dir)
+        .min_depth(1)
+        .sort_by_file_name()
+    {
+        let rel_path = entry?.path().strip_prefix(base_dir)?.to_path_buf();
+        if !metadata_files.contains(&rel_path) {
+            entries.push(rel_path);
+        }
+    }
+
+    Ok(entries)
+}
@@ -1,56 +1,73 @@
-use crate::metadata;
+use crate::arch;
+use crate::metadata::Metadata;
+use crate::reproducible;
+use crate::version;
 use alpm_buildinfo::BuildInfoV2;
-use alpm_types::{BuildDate, FromOffsetDateTime, MetadataFileName};
-use std::fs::File;
+use alpm_types::{InstalledPackage, MetadataFileName};
+use std::fs::{self, File};
 use std::io::Write;
 use std::path::Path;
 use std::str::FromStr;
-use time::OffsetDateTime;
 
+/// Build date for .BUILDINFO and .PKGINFO, honouring `SOURCE_DATE_EPOCH`.
 pub fn get_build_date() -> u64 {
-    let now = OffsetDateTime::now_utc();
-    // BuildDate might be i64 internally, cast to u64 for compatibility with the rest of the code
-    i64::from(BuildDate::from_offset_datetime(now)) as u64
+    reproducible::build_date()
 }
 
-pub fn create_build_info(input_path: impl AsRef<Path>) -> Result<(), Box<dyn std::error::Error>> {
+pub fn create_build_info(
+    input_path: impl AsRef<Path>,
+    metadata: &Metadata,
+) -> Result<(), Box<dyn std::error::Error>> {
     let input_path = input_path.as_ref();
     let buildinfo_path = input_path.join(MetadataFileName::BuildInfo.as_ref());
     let mut file = File::create(&buildinfo_path)?;
 
-    let metadata = metadata::extract_metadata("metadata")?;
-
     let mut buildenv_str = String::new();
     for opt in &metadata.alpm_build_env {
         buildenv_str.push_str(&format!("buildenv = {}\n", opt));
     }
+    let mut options_str = String::new();
+    for opt in &metadata.options {
+        options_str.push_str(&format!("options = {}\n", opt));
+    }
+
+    let mut installed_str = String::new();
+    for package in installed_packages() {
+        installed_str.push_str(&format!("installed = {}\n", package));
+    }
+
+    // Sources are unpacked next to the metadata file, so it is both the start and build directory
+    let cwd = std::env::current_dir()?;
 
     let buildinfo_data = format!(
         r#"format = 2
 pkgname = {}
 pkgbase = {}
-pkgver = {}-{}
+pkgver = {}
 pkgarch = {}
 pkgbuild_sha256sum = {}
 packager = {} <{}>
 builddate = {}
-builddir = /build
-startdir = /startdir/
-buildtool = devtools
-buildtoolver = 1:1.2.1-1-any
-{}
-installed = bar-1.2.3-1-any
-"#,
+builddir = {}
+startdir = {}
+buildtool = {}
+buildtoolver = {}
+{}{}{}"#,
         metadata.name,
-        metadata.name,
-        metadata.version,
-        metadata.release,
-        metadata.arch.first().unwrap_or(&"any".to_string()),
+        metadata.pkgbase,
+        version::full_version(metadata),
+        arch::lookup(&metadata.target_arch)?.alpm,
         metadata.pkgbuild_sha256sum,
         metadata.maintainer,
         metadata.email,
         get_build_date(),
-        buildenv_str
+        cwd.display(),
+        cwd.display(),
+        env!("CARGO_PKG_NAME"),
+        env!("CARGO_PKG_VERSION"),
+        buildenv_str,
+        options_str,
+        installed_str
     );
 
     let buildinfo = BuildInfoV2::from_str(&buildinfo_data)?;
@@ -59,3 +76,32 @@ installed = bar-1.2.3-1-any
 
     Ok(())
 }
+
+/// Packages installed on the build host as `name-version-arch`, read from the local pacman
+/// database. Empty when the host is not an ALPM system.
+fn installed_packages() -> Vec<String> {
+    let Ok(entries) = fs::read_dir("/var/lib/pacman/local") else {
+        return Vec::new();
+    };
+
+    let mut packages: Vec<String> = entries
+        .filter_map(|entry| fs::read_to_string(entry.ok()?.path().join("desc")).ok())
+        .filter_map(|desc| {
+            // desc holds `%FIELD%` headers, each followed by its value on the next line
+            let field = |name: &str| {
+                let mut lines = desc.lines();
+                lines.find(|line| *line == name)?;
+                lines.next().map(str::to_string)
+            };
+            Some(format!(
+                "{}-{}-{}",
+                field("%NAME%")?,
+                field("%VERSION%")?,
+                field("%ARCH%")?
+            ))
+        })
+        .filter(|package| InstalledPackage::from_str(package).is_ok())
+        .collect();
+    packages.sort();
+    packages
+}
@@ -1,4 +1,5 @@
 pub mod alpm;
 pub mod build_info;
+pub mod mtree;
 pub mod pkg_info;
 pub use alpm::alpm_build;
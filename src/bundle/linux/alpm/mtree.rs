@@ -0,0 +1,99 @@
+use crate::attributes::AttributeTable;
+use crate::reproducible;
+use alpm_mtree::Mtree;
+use alpm_types::MetadataFileName;
+use flate2::Compression;
+use flate2::write::GzEncoder;
+use sha2::{Digest, Sha256};
+use std::fmt::Write as _;
+use std::fs::{self, File};
+use std::io::Write;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+use std::str::FromStr;
+
+/// Encode a path the way bsdtar writes ALPM-MTREE paths: `\s`-style escapes for whitespace and
+/// `#`, octal triplets for every other byte outside printable ASCII.
+fn mtree_escape(path: &str) -> String {
+    let mut escaped = String::new();
+    for byte in path.bytes() {
+        match byte {
+            b' ' => escaped.push_str("\\s"),
+            b'\t' => escaped.push_str("\\t"),
+            b'\r' => escaped.push_str("\\r"),
+            b'\n' => escaped.push_str("\\n"),
+            b'#' => escaped.push_str("\\#"),
+            b'\\' => escaped.push_str("\\134"),
+            0x21..=0x7e => escaped.push(byte as char),
+            _ => {
+                let _ = write!(escaped, "\\{:03o}", byte);
+            }
+        }
+    }
+    escaped
+}
+
+/// Create the ALPM-MTREEv2 file for a staged package. Unlike bsdtar, owners and modes are
+/// taken from the attribute table, so the result does not depend on who ran the build.
+pub fn create_mtree(
+    input_path: impl AsRef<Path>,
+    attributes: &AttributeTable,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let input_path = input_path.as_ref();
+    let mtree_name = MetadataFileName::Mtree.as_ref();
+
+    let mut contents = String::from("#mtree\n");
+    for entry in walkdir::WalkDir::new(input_path)
+        .min_depth(1)
+        .sort_by_file_name()
+    {
+        let entry = entry?;
+        let rel_path = entry.path().strip_prefix(input_path)?;
+        if rel_path == Path::new(mtree_name) {
+            continue;
+        }
+
+        let metadata = entry.path().symlink_metadata()?;
+        let attrs = attributes.get(input_path, rel_path)?;
+        write!(
+            contents,
+            "./{} time={}.0 mode={:o} uid={} gid={}",
+            mtree_escape(&rel_path.to_string_lossy()),
+            reproducible::clamp_mtime(metadata.mtime() as u64),
+            attrs.mode,
+            attrs.uid,
+            attrs.gid
+        )?;
+
+        let file_type = entry.file_type();
+        if file_type.is_symlink() {
+            let target = fs::read_link(entry.path())?;
+            write!(
+                contents,
+                " type=link link={}",
+                mtree_escape(&target.to_string_lossy())
+            )?;
+        } else if file_type.is_dir() {
+            write!(contents, " type=dir")?;
+        } else {
+            let digest = Sha256::digest(fs::read(entry.path())?);
+            write!(
+                contents,
+                " type=file size={} sha256digest={}",
+                metadata.len(),
+                hex::encode(digest)
+            )?;
+        }
+        contents.push('\n');
+    }
+
+    // Make sure the generated data parses as valid ALPM-MTREE before writing it
+    Mtree::from_str(&contents)?;
+
+    let file = File::create(input_path.join(mtree_name))?;
+    let mut encoder = GzEncoder::new(file, Compression::default());
+    encoder.write_all(contents.as_bytes())?;
+    encoder.finish()?;
+
+    Ok(())
+}
@@ -1,4 +1,6 @@
-use crate::metadata;
+use crate::arch;
+use crate::metadata::Metadata;
+use crate::version;
 use alpm_types::MetadataFileName;
 use std::fs::File;
 use std::io::Write;
@@ -10,25 +12,53 @@ pub fn get_installed_size(input_path: impl AsRef<Path>) -> u64 {
         .into_iter()
         .filter_map(|e| e.ok())
     {
-        if let Ok(metadata) = entry.metadata() {
-            if metadata.is_file() {
-                total_size += metadata.len();
-            }
+        if let Ok(metadata) = entry.metadata()
+            && metadata.is_file()
+        {
+            total_size += metadata.len();
         }
     }
     total_size
 }
 
-pub fn create_package_info(input_path: impl AsRef<Path>) -> Result<(), Box<dyn std::error::Error>> {
+pub fn create_package_info(
+    input_path: impl AsRef<Path>,
+    metadata: &Metadata,
+) -> Result<(), Box<dyn std::error::Error>> {
     let input_path = input_path.as_ref();
-    let mut file = File::create(&input_path.join(MetadataFileName::PackageInfo.as_ref()))?;
-    let metadata = metadata::extract_metadata("metadata")?;
+    let mut file = File::create(input_path.join(MetadataFileName::PackageInfo.as_ref()))?;
+
+    // Split packages may clear any of these lists, so each entry gets its own line
+    let mut relations_str = String::new();
+    for (key, values) in [
+        ("conflict", &metadata.conflicts),
+        ("provides", &metadata.provides),
+    ] {
+        for value in values {
+            relations_str.push_str(&format!("{} = {}\n", key, value));
+        }
+    }
+    // Backup paths are relative to the package root in .PKGINFO
+    for path in &metadata.backup {
+        relations_str.push_str(&format!("backup = {}\n", path.trim_start_matches('/')));
+    }
+    for depend in &metadata.alpm_depends {
+        relations_str.push_str(&format!("depend = {}\n", depend));
+    }
+    let pkgtype = if metadata.is_debug {
+        "debug"
+    } else if metadata.is_split {
+        "split"
+    } else {
+        "pkg"
+    };
+
     write!(
         file,
         r#"pkgname = {}
 pkgbase = {}
-xdata = pkgtype=pkg
-pkgver = {}-{}
+xdata = pkgtype={}
+pkgver = {}
 pkgdesc = {}
 url = {}
 builddate = {}
@@ -36,25 +66,20 @@ packager = {} <{}>
 size = {}
 arch = {}
 license = {}
-conflict = {}
-provides = {}
-depend = {}
-"#,
-        metadata.name,
+{}"#,
         metadata.name,
-        metadata.version,
-        metadata.release,
+        metadata.pkgbase,
+        pkgtype,
+        version::full_version(metadata),
         metadata.description,
         metadata.url,
         super::build_info::get_build_date(),
         metadata.maintainer,
         metadata.email,
         get_installed_size(input_path),
-        metadata.arch.first().unwrap_or(&"any".to_string()),
+        arch::lookup(&metadata.target_arch)?.alpm,
         metadata.license,
-        metadata.conflicts[0],
-        metadata.provides[0],
-        metadata.alpm_depends.join("\ndepend = ")
+        relations_str
     )?;
     Ok(())
 }
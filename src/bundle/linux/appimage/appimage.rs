@@ -1,33 +1,105 @@
 use std::fs::File;
 use std::io::{BufReader, BufWriter, Write};
 use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
 
+use crate::arch;
+use crate::bundle::linux::appimage::libraries::bundle_libraries;
+use crate::bundle::linux::appimage::runtime::{self, UPDATE_INFO_SECTION};
+use crate::bundle::linux::appimage::signature::sign_appimage;
 use crate::bundle::linux::appimage::squashfs::squashfs_build;
+use crate::bundle::linux::appimage::zsync;
 use crate::chmod::chmod_package;
+use crate::compression::{self, Algorithm, Compression};
+use crate::desktop;
 use crate::metadata;
+use crate::strip;
 
-static RUNTIME: &[u8] = include_bytes!("apprun/runtime-x86_64");
-
-pub fn appimage_build() -> Result<(), Box<dyn std::error::Error>> {
+pub fn appimage_build(
+    arch: &str,
+    compression: Option<&str>,
+) -> Result<(), Box<dyn std::error::Error>> {
     //Extract metadata
-    let metadata = metadata::extract_metadata("metadata")?;
+    let metadata = metadata::extract_metadata_for_arch("metadata", Some(arch))?;
 
     //Check if metadata is valid
-    if metadata.appimage_exec.is_empty() {
-        return Err("appimage_exec not found".into());
+    let arch_names = arch::lookup(&metadata.target_arch)?;
+    let Some(runtime_arch) = arch_names.appimage else {
+        println!(
+            "Skipping AppImage: no runtime exists for {}",
+            metadata.target_arch
+        );
+        return Ok(());
+    };
+    let mut runtime = runtime::load_runtime(&metadata, arch_names, runtime_arch)?;
+    if !metadata.appimage_update_info.is_empty() {
+        runtime::check_update_info(&metadata.appimage_update_info)?;
+        runtime::write_section(
+            &mut runtime,
+            UPDATE_INFO_SECTION,
+            metadata.appimage_update_info.as_bytes(),
+        )?;
     }
 
-    if metadata.appimage_desktop_instructions.is_empty() {
-        return Err("appimage_desktop not found".into());
+    if metadata.appimage_exec.is_empty() {
+        return Err("appimage_exec not found".into());
     }
 
-    if metadata.appimage_icon_instructions.is_empty() {
-        return Err("appimage_icon not found".into());
-    }
+    let compression = compression::resolve(
+        "AppImage",
+        compression,
+        &metadata.appimage_compression,
+        Compression::new(Algorithm::Zstd, Some(20)),
+        &[
+            Algorithm::Zstd,
+            Algorithm::Xz,
+            Algorithm::Gzip,
+            Algorithm::Lz4,
+        ],
+    )?;
 
     //Create AppDir
     let base_dir = format!("{}.AppDir", metadata.name);
-    chmod_package(&base_dir, true)?;
+    // Split packages: the AppImage bundles the package named after the pkgbase, or the first
+    let packages = metadata.split_packages();
+    let package = packages
+        .iter()
+        .find(|package| package.name == metadata.pkgbase)
+        .unwrap_or(&packages[0]);
+
+    // The desktop entry is either generated from the desktop block or installed by hand
+    match (
+        package.desktop.is_empty(),
+        package.appimage_desktop_instructions.is_empty(),
+    ) {
+        (true, true) => return Err("appimage_desktop or desktop not found".into()),
+        (false, false) => {
+            return Err(
+                "Give either a desktop block or an appimage_desktop block, not both".into(),
+            );
+        }
+        _ => {}
+    }
+    match (
+        package.icon.is_empty(),
+        package.appimage_icon_instructions.is_empty(),
+    ) {
+        (true, true) => return Err("appimage_icon or icon not found".into()),
+        (false, false) => {
+            return Err("Give either an icon or an appimage_icon block, not both".into());
+        }
+        _ => {}
+    }
+
+    let mut attributes = chmod_package(&base_dir, package, true)?;
+    let library_dirs = if metadata.appimage_bundle_libs {
+        bundle_libraries(&base_dir, package, &mut attributes)?
+    } else {
+        Vec::new()
+    };
+    strip::strip_package(&base_dir, package)?;
+    desktop::validate_appdir(Path::new(&base_dir), package)?;
+    runtime::check_appdir_machines(Path::new(&base_dir), arch_names)?;
 
     //Create output directory
     let output_dir = std::env::current_dir()?.join("output");
@@ -37,16 +109,29 @@ pub fn appimage_build() -> Result<(), Box<dyn std::error::Error>> {
 
     let base_name = format!(
         "{}-{}-{}-{}",
-        metadata.name, metadata.version, metadata.release, metadata.arch[0]
+        metadata.name, metadata.version, metadata.release, runtime_arch
     );
 
     //Add AppRun
     let apprun_path = std::path::Path::new(&base_dir).join("AppRun");
     let mut file = File::create(&apprun_path)?;
 
+    // Bundled libraries are found through LD_LIBRARY_PATH rather than a patched RPATH
+    let library_path = if library_dirs.is_empty() {
+        String::new()
+    } else {
+        let dirs: Vec<String> = library_dirs
+            .iter()
+            .map(|dir| format!("$HERE/{}", dir.display()))
+            .collect();
+        format!(
+            "export LD_LIBRARY_PATH=\"{}${{LD_LIBRARY_PATH:+:$LD_LIBRARY_PATH}}\"\n",
+            dirs.join(":")
+        )
+    };
     let content = format!(
-        "#!/bin/bash\nHERE=\"$(dirname \"$(readlink -f \"$0\")\")\"\nexec \"$HERE{}\" \"$@\"\n",
-        metadata.appimage_exec
+        "#!/bin/bash\nHERE=\"$(dirname \"$(readlink -f \"$0\")\")\"\n{}exec \"$HERE{}\" \"$@\"\n",
+        library_path, metadata.appimage_exec
     );
 
     file.write_all(content.as_bytes())?;
@@ -55,7 +140,7 @@ pub fn appimage_build() -> Result<(), Box<dyn std::error::Error>> {
     file.set_permissions(std::fs::Permissions::from_mode(0o755))?;
 
     //Squashfs build
-    squashfs_build(&base_dir, &base_name)?;
+    squashfs_build(&base_dir, &base_name, &attributes, compression)?;
 
     //Remove AppDir
     //std::fs::remove_dir_all(&base_dir)?;
@@ -64,14 +149,28 @@ pub fn appimage_build() -> Result<(), Box<dyn std::error::Error>> {
     let mut squashfs_data = BufReader::new(File::open(&squashfs_path)?);
 
     //Create AppImage with 755 permission
-    let mut output_file = File::create(output_dir.join(format!("{}.AppImage", base_name)))?;
+    let appimage_name = format!("{}.AppImage", base_name);
+    let appimage_path = output_dir.join(&appimage_name);
+    let mut output_file = File::create(&appimage_path)?;
 
     #[cfg(unix)]
     output_file.set_permissions(std::fs::Permissions::from_mode(0o755))?;
 
     let mut out_writer = BufWriter::new(&mut output_file);
-    out_writer.write_all(RUNTIME)?;
+    out_writer.write_all(&runtime)?;
     std::io::copy(&mut squashfs_data, &mut out_writer)?;
+    out_writer.flush()?;
+    drop(out_writer);
+
+    // Signing covers the whole file, so it comes after everything but the zsync file
+    if !metadata.appimage_signing_key.is_empty() {
+        sign_appimage(&appimage_path, &metadata.appimage_signing_key)?;
+    }
+
+    // AppImageUpdate fetches the control file next to the AppImage it finds through .upd_info
+    if !metadata.appimage_update_info.is_empty() {
+        zsync::write_control_file(&appimage_path, &appimage_name)?;
+    }
 
     //Remove squashfs file
     //std::fs::remove_file(&squashfs_path)?;
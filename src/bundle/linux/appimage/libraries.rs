@@ -0,0 +1,178 @@
+use crate::arch;
+use crate::attributes::{AttributeTable, FileAttributes};
+use crate::elf::{self, DynamicInfo};
+use crate::metadata::Metadata;
+use std::collections::VecDeque;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Libraries every host is expected to provide, after the AppImage project's excludelist:
+/// glibc, the graphics and audio stack that has to match the host drivers and daemons, and
+/// core libraries whose bundled copies break the ones above.
+const EXCLUDED_LIBRARIES: &[&str] = &[
+    "libanl.so.1",
+    "libBrokenLocale.so.1",
+    "libc.so.6",
+    "libdl.so.2",
+    "libm.so.6",
+    "libmvec.so.1",
+    "libnsl.so.1",
+    "libpthread.so.0",
+    "libresolv.so.2",
+    "librt.so.1",
+    "libthread_db.so.1",
+    "libutil.so.1",
+    "libGL.so.1",
+    "libGLX.so.0",
+    "libGLdispatch.so.0",
+    "libOpenGL.so.0",
+    "libEGL.so.1",
+    "libGLESv2.so.2",
+    "libglapi.so.0",
+    "libgbm.so.1",
+    "libdrm.so.2",
+    "libvulkan.so.1",
+    "libxcb.so.1",
+    "libX11.so.6",
+    "libX11-xcb.so.1",
+    "libICE.so.6",
+    "libSM.so.6",
+    "libasound.so.2",
+    "libjack.so.0",
+    "libpipewire-0.3.so.0",
+    "libfontconfig.so.1",
+    "libfreetype.so.6",
+    "libharfbuzz.so.0",
+    "libexpat.so.1",
+    "libz.so.1",
+    "libcom_err.so.2",
+    "libgpg-error.so.0",
+    "libusb-1.0.so.0",
+    "libuuid.so.1",
+];
+
+/// Where bundled libraries are copied, relative to the AppDir.
+const LIB_DIR: &str = "usr/lib";
+
+fn is_excluded(soname: &str, metadata: &Metadata) -> bool {
+    soname.starts_with("ld-linux")
+        || soname.starts_with("ld64.so")
+        || EXCLUDED_LIBRARIES.contains(&soname)
+        || metadata.appimage_lib_exclude.iter().any(|s| s == soname)
+}
+
+/// Resolve the `DT_NEEDED` entries of the ELF files in `app_dir` recursively against
+/// `appimage_sysroot` and copy every library that is not excluded into `usr/lib`, as
+/// linuxdeploy does. Returns the AppDir directories holding shared libraries, in the order
+/// they belong in `LD_LIBRARY_PATH`.
+pub fn bundle_libraries(
+    app_dir: &str,
+    metadata: &Metadata,
+    attributes: &mut AttributeTable,
+) -> Result<Vec<PathBuf>, Box<dyn std::error::Error>> {
+    let base_dir = Path::new(app_dir);
+    let sysroot = Path::new(if metadata.appimage_sysroot.is_empty() {
+        "/"
+    } else {
+        &metadata.appimage_sysroot
+    });
+    if !sysroot.is_dir() {
+        return Err(format!("appimage_sysroot {} is not a directory", sysroot.display()).into());
+    }
+    let multiarch = arch::lookup(&metadata.target_arch)?.multiarch;
+    let search_dirs: Vec<PathBuf> = [
+        format!("lib/{}", multiarch),
+        format!("usr/lib/{}", multiarch),
+        "lib64".to_string(),
+        "usr/lib64".to_string(),
+        "lib".to_string(),
+        "usr/lib".to_string(),
+    ]
+    .iter()
+    .map(|dir| sysroot.join(dir))
+    .collect();
+
+    // Libraries the package ships itself are found by file name or soname
+    let mut provided = Vec::new();
+    let mut library_dirs = vec![PathBuf::from(LIB_DIR)];
+    let mut queue = VecDeque::new();
+    for rel_path in elf::staged_elf_files(base_dir)? {
+        let path = base_dir.join(&rel_path);
+        let Some(info) = elf::dynamic_info(&fs::read(&path)?)? else {
+            continue;
+        };
+        if let Some(soname) = &info.soname {
+            provided.push(soname.clone());
+            if let Some(dir) = rel_path.parent() {
+                library_dirs.push(dir.to_path_buf());
+            }
+        }
+        if let Some(name) = rel_path.file_name() {
+            provided.push(name.to_string_lossy().into_owned());
+        }
+        queue.push_back((format!("/{}", rel_path.display()), path, info));
+    }
+
+    while let Some((name, path, info)) = queue.pop_front() {
+        for soname in &info.needed {
+            if provided.contains(soname) || is_excluded(soname, metadata) {
+                continue;
+            }
+            let (source, library) = find_library(soname, &path, &info, sysroot, &search_dirs)?
+                .ok_or_else(|| {
+                    format!(
+                        "Library {} needed by {} not found in {}",
+                        soname,
+                        name,
+                        sysroot.display()
+                    )
+                })?;
+
+            let rel_path = Path::new(LIB_DIR).join(soname);
+            fs::create_dir_all(base_dir.join(LIB_DIR))?;
+            fs::copy(&source, base_dir.join(&rel_path))?;
+            attributes.insert(&rel_path, FileAttributes::root(0o755));
+            println!("Bundling /{} from {}", rel_path.display(), source.display());
+
+            provided.push(soname.clone());
+            queue.push_back((format!("/{}", rel_path.display()), source, library));
+        }
+    }
+
+    library_dirs.sort();
+    library_dirs.dedup();
+    Ok(library_dirs)
+}
+
+/// Look `soname` up in the run path of the file needing it, then in the library directories of
+/// the sysroot. Only libraries of the same ELF class and machine qualify.
+fn find_library(
+    soname: &str,
+    needed_by: &Path,
+    info: &DynamicInfo,
+    sysroot: &Path,
+    search_dirs: &[PathBuf],
+) -> Result<Option<(PathBuf, DynamicInfo)>, Box<dyn std::error::Error>> {
+    let origin = needed_by.parent().unwrap_or(Path::new("/"));
+    let runpath = info.runpath.iter().map(|dir| {
+        let dir = dir.replace("${ORIGIN}", "$ORIGIN");
+        match dir.strip_prefix("$ORIGIN") {
+            Some(rest) => origin.join(rest.trim_start_matches('/')),
+            None => sysroot.join(dir.trim_start_matches('/')),
+        }
+    });
+
+    for dir in runpath.chain(search_dirs.iter().cloned()) {
+        let candidate = dir.join(soname);
+        if !candidate.is_file() {
+            continue;
+        }
+        let Some(library) = elf::dynamic_info(&fs::read(&candidate)?).ok().flatten() else {
+            continue;
+        };
+        if library.is_64 == info.is_64 && library.machine == info.machine {
+            return Ok(Some((candidate, library)));
+        }
+    }
+    Ok(None)
+}
@@ -1,4 +1,9 @@
 pub mod appimage;
+pub mod libraries;
+pub mod runtime;
+pub mod signature;
 pub mod squashfs;
+pub mod zsync;
 
 pub use appimage::appimage_build;
+pub use signature::verify_appimage;
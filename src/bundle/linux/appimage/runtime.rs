@@ -0,0 +1,193 @@
+use crate::arch::ArchNames;
+use crate::elf;
+use crate::metadata::Metadata;
+use object::read::{Object, ObjectSection};
+use std::fs;
+use std::ops::Range;
+use std::path::Path;
+
+static RUNTIME_X86_64: &[u8] = include_bytes!("apprun/runtime-x86_64");
+static RUNTIME_I686: &[u8] = include_bytes!("apprun/runtime-i686");
+static RUNTIME_AARCH64: &[u8] = include_bytes!("apprun/runtime-aarch64");
+static RUNTIME_ARMHF: &[u8] = include_bytes!("apprun/runtime-armhf");
+
+/// Section of the runtime holding the AppImageUpdate transport string.
+pub const UPDATE_INFO_SECTION: &str = ".upd_info";
+
+/// The embedded AppImage runtime for an architecture's runtime name.
+fn runtime_for(runtime_arch: &str) -> Result<&'static [u8], Box<dyn std::error::Error>> {
+    match runtime_arch {
+        "x86_64" => Ok(RUNTIME_X86_64),
+        "i686" => Ok(RUNTIME_I686),
+        "aarch64" => Ok(RUNTIME_AARCH64),
+        "armhf" => Ok(RUNTIME_ARMHF),
+        _ => Err(format!("No AppImage runtime embedded for {}", runtime_arch).into()),
+    }
+}
+
+/// The runtime for `arch`: the `appimage_runtime` file if given, otherwise the embedded one.
+/// Fails unless the runtime is built for `arch`.
+pub fn load_runtime(
+    metadata: &Metadata,
+    arch: &ArchNames,
+    runtime_arch: &str,
+) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let (runtime, source) = if metadata.appimage_runtime.is_empty() {
+        (
+            runtime_for(runtime_arch)?.to_vec(),
+            format!("embedded runtime-{}", runtime_arch),
+        )
+    } else {
+        let runtime = fs::read(&metadata.appimage_runtime).map_err(|e| {
+            format!(
+                "Failed to read appimage_runtime {}: {}",
+                metadata.appimage_runtime, e
+            )
+        })?;
+        (runtime, metadata.appimage_runtime.clone())
+    };
+    check_machine(&runtime, arch).map_err(|e| format!("AppImage runtime {}: {}", source, e))?;
+    Ok(runtime)
+}
+
+/// Fail with the architecture found when the ELF file `data` is not built for `arch`.
+pub fn check_machine(data: &[u8], arch: &ArchNames) -> Result<(), Box<dyn std::error::Error>> {
+    let machine = elf::machine(data)?;
+    if arch.elf_machine == Some(machine) {
+        return Ok(());
+    }
+    let found = crate::arch::by_machine(machine)
+        .map(|found| found.canonical.to_string())
+        .unwrap_or_else(|| format!("ELF machine {}", machine.0));
+    Err(format!("built for {}, not {}", found, arch.canonical).into())
+}
+
+/// Check that every ELF file staged in the AppDir runs on `arch`, so that a binary built for
+/// another architecture cannot end up behind the runtime.
+pub fn check_appdir_machines(
+    app_dir: &Path,
+    arch: &ArchNames,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut mismatches = Vec::new();
+    for rel_path in elf::staged_elf_files(app_dir)? {
+        if let Err(e) = check_machine(&fs::read(app_dir.join(&rel_path))?, arch) {
+            mismatches.push(format!("/{}: {}", rel_path.display(), e));
+        }
+    }
+    if !mismatches.is_empty() {
+        return Err(format!(
+            "The AppDir contains ELF files for another architecture than {}: {}",
+            arch.canonical,
+            mismatches.join("; ")
+        )
+        .into());
+    }
+    Ok(())
+}
+
+/// File offsets of the section `name` of the runtime, which reserves zero-filled space for
+/// the update information and the signature.
+pub fn section_range(data: &[u8], name: &str) -> Result<Range<usize>, Box<dyn std::error::Error>> {
+    let file = object::File::parse(data)?;
+    let section = file
+        .section_by_name(name)
+        .ok_or_else(|| format!("The AppImage runtime has no {} section", name))?;
+    let (offset, size) = section
+        .file_range()
+        .ok_or_else(|| format!("The {} section of the AppImage runtime is empty", name))?;
+    Ok(offset as usize..(offset + size) as usize)
+}
+
+/// Write `contents` into the section `name` of `data`, zero-filling the rest. At least one NUL
+/// has to remain so that readers find the end of a string.
+pub fn write_section(
+    data: &mut [u8],
+    name: &str,
+    contents: &[u8],
+) -> Result<(), Box<dyn std::error::Error>> {
+    let range = section_range(data, name)?;
+    if contents.len() >= range.len() {
+        return Err(format!(
+            "{} bytes do not fit the {} section of {} bytes",
+            contents.len(),
+            name,
+            range.len()
+        )
+        .into());
+    }
+    let section = &mut data[range];
+    section.fill(0);
+    section[..contents.len()].copy_from_slice(contents);
+    Ok(())
+}
+
+/// Contents of the section `name` of `data` up to the first NUL.
+pub fn read_section<'a>(
+    data: &'a [u8],
+    name: &str,
+) -> Result<&'a [u8], Box<dyn std::error::Error>> {
+    let section = &data[section_range(data, name)?];
+    let end = section
+        .iter()
+        .position(|&b| b == 0)
+        .unwrap_or(section.len());
+    Ok(&section[..end])
+}
+
+/// Check an `appimage_update_info` string against the transports AppImageUpdate knows:
+/// `zsync|URL`, `gh-releases-zsync|USER|REPO|TAG|FILENAME` and `pling-v1-zsync|ID|FILENAME`.
+pub fn check_update_info(update_info: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let fields: Vec<&str> = update_info.split('|').collect();
+    let expected = match fields[0] {
+        "zsync" => 2,
+        "gh-releases-zsync" => 5,
+        "pling-v1-zsync" => 3,
+        transport => {
+            return Err(format!(
+                "Unknown appimage_update_info transport '{}' \
+                 (expected zsync, gh-releases-zsync or pling-v1-zsync)",
+                transport
+            )
+            .into());
+        }
+    };
+    if fields.len() != expected || fields.iter().any(|field| field.is_empty()) {
+        return Err(format!(
+            "appimage_update_info for {} needs {} non-empty '|'-separated fields: {}",
+            fields[0], expected, update_info
+        )
+        .into());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn update_info_reads_back() {
+        let update_info = "gh-releases-zsync|JP-Makers|twincan|latest|*x86_64.AppImage";
+        check_update_info(update_info).unwrap();
+
+        let mut runtime = RUNTIME_X86_64.to_vec();
+        assert_eq!(read_section(&runtime, UPDATE_INFO_SECTION).unwrap(), b"");
+        write_section(&mut runtime, UPDATE_INFO_SECTION, update_info.as_bytes()).unwrap();
+        assert_eq!(
+            read_section(&runtime, UPDATE_INFO_SECTION).unwrap(),
+            update_info.as_bytes()
+        );
+        // Only the section changes, so the runtime still parses as the same ELF file
+        assert_eq!(runtime.len(), RUNTIME_X86_64.len());
+        object::File::parse(runtime.as_slice()).unwrap();
+    }
+
+    #[test]
+    fn update_info_too_long() {
+        let mut runtime = RUNTIME_X86_64.to_vec();
+        let update_info = format!("zsync|https://example.com/{}", "a".repeat(1024));
+        assert!(write_section(&mut runtime, UPDATE_INFO_SECTION, update_info.as_bytes()).is_err());
+        assert!(check_update_info("zsync").is_err());
+        assert!(check_update_info("bintray-zsync|a|b|c|d").is_err());
+    }
+}
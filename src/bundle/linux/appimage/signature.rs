@@ -0,0 +1,272 @@
+use crate::bundle::linux::appimage::runtime;
+use crate::reproducible;
+use pgp::composed::{
+    ArmorOptions, Deserializable, DetachedSignature, SignedPublicKey, SignedSecretKey,
+    SubpacketConfig,
+};
+use pgp::crypto::hash::HashAlgorithm;
+use pgp::packet::{Subpacket, SubpacketData};
+use pgp::types::{KeyDetails, KeyVersion, Password, PublicKeyTrait};
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::path::Path;
+
+/// Runtime section holding the ASCII-armored detached signature.
+pub const SIGNATURE_SECTION: &str = ".sha256_sig";
+
+/// Runtime section holding the ASCII-armored public key the signature was made with.
+pub const KEY_SECTION: &str = ".sig_key";
+
+/// Environment variable holding the passphrase of `appimage_signing_key`, if it has one.
+const PASSPHRASE_VAR: &str = "APPIMAGE_SIGN_PASSPHRASE";
+
+/// The hex SHA-256 of an AppImage with the signature and key sections read as zeros, which
+/// is what the AppImage signing spec signs.
+fn digest(data: &[u8]) -> Result<String, Box<dyn std::error::Error>> {
+    let mut ranges = vec![
+        runtime::section_range(data, SIGNATURE_SECTION)?,
+        runtime::section_range(data, KEY_SECTION)?,
+    ];
+    ranges.sort_by_key(|range| range.start);
+
+    let mut hasher = Sha256::new();
+    let mut position = 0;
+    for range in ranges {
+        hasher.update(&data[position..range.start]);
+        hasher.update(vec![0u8; range.len()]);
+        position = range.end;
+    }
+    hasher.update(&data[position..]);
+    Ok(hex::encode(hasher.finalize()))
+}
+
+/// Sign the AppImage at `path` in place with the ASCII-armored OpenPGP secret key in
+/// `key_path`, like `appimagetool --sign`: a detached signature of the digest goes into
+/// `.sha256_sig` and the public key into `.sig_key`.
+pub fn sign_appimage(path: &Path, key_path: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let armored = fs::read_to_string(key_path)
+        .map_err(|e| format!("Failed to read appimage_signing_key {}: {}", key_path, e))?;
+    let (key, _) = SignedSecretKey::from_string(&armored)
+        .map_err(|e| format!("appimage_signing_key {}: {}", key_path, e))?;
+    let password = std::env::var(PASSPHRASE_VAR)
+        .map(Password::from)
+        .unwrap_or_else(|_| Password::empty());
+
+    let mut data = fs::read(path)?;
+    let digest = digest(&data)?;
+
+    // The creation time follows SOURCE_DATE_EPOCH so signed builds stay reproducible, but a
+    // signature older than its key is rejected by verifiers
+    let created = chrono::DateTime::from_timestamp(reproducible::build_date() as i64, 0)
+        .ok_or("Invalid signature creation time")?
+        .max(*key.primary_key.public_key().created_at());
+    let hashed = vec![
+        Subpacket::regular(SubpacketData::IssuerFingerprint(key.fingerprint()))?,
+        Subpacket::regular(SubpacketData::SignatureCreationTime(created))?,
+    ];
+    let mut unhashed = Vec::new();
+    if key.version() <= KeyVersion::V4 {
+        unhashed.push(Subpacket::regular(SubpacketData::Issuer(key.key_id()))?);
+    }
+    let signature = DetachedSignature::sign_binary_data_with_subpackets(
+        rand::thread_rng(),
+        &key.primary_key,
+        &password,
+        HashAlgorithm::Sha256,
+        digest.as_bytes(),
+        SubpacketConfig::UserDefined { hashed, unhashed },
+    )
+    .map_err(|e| format!("Failed to sign {:?}: {}", path, e))?;
+
+    let signature = signature.to_armored_string(ArmorOptions::default())?;
+    let public_key = key
+        .signed_public_key()
+        .to_armored_string(ArmorOptions::default())?;
+    runtime::write_section(&mut data, SIGNATURE_SECTION, signature.as_bytes())?;
+    runtime::write_section(&mut data, KEY_SECTION, public_key.as_bytes())?;
+    fs::write(path, data)?;
+
+    println!("Signed {:?} with key {}", path, key.fingerprint());
+    Ok(())
+}
+
+/// Check the signature embedded in the AppImage at `path` against `trusted_key`: either an
+/// ASCII-armored public key file or the fingerprint of the key the AppImage has to be signed
+/// with. The public key embedded next to the signature is only used when it has that
+/// fingerprint, so a file re-signed with another key is rejected. Returns a description of the
+/// signer.
+pub fn verify_appimage(
+    path: &Path,
+    trusted_key: &str,
+) -> Result<String, Box<dyn std::error::Error>> {
+    let data = fs::read(path)?;
+    let signature = runtime::read_section(&data, SIGNATURE_SECTION)?;
+    let public_key = runtime::read_section(&data, KEY_SECTION)?;
+    if signature.is_empty() || public_key.is_empty() {
+        return Err(format!("{:?} is not signed", path).into());
+    }
+
+    let (signature, _) = DetachedSignature::from_string(std::str::from_utf8(signature)?)
+        .map_err(|e| format!("Invalid signature in {}: {}", SIGNATURE_SECTION, e))?;
+    let (public_key, _) = SignedPublicKey::from_string(std::str::from_utf8(public_key)?)
+        .map_err(|e| format!("Invalid public key in {}: {}", KEY_SECTION, e))?;
+
+    let public_key = if Path::new(trusted_key).is_file() {
+        let armored = fs::read_to_string(trusted_key)?;
+        SignedPublicKey::from_string(&armored)
+            .map_err(|e| format!("Invalid public key {}: {}", trusted_key, e))?
+            .0
+    } else {
+        let fingerprint = trusted_key
+            .trim_start_matches("0x")
+            .replace(char::is_whitespace, "")
+            .to_ascii_lowercase();
+        if fingerprint.is_empty() || !fingerprint.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(format!(
+                "--key must be a public key file or a key fingerprint: {}",
+                trusted_key
+            )
+            .into());
+        }
+        let fingerprints: Vec<String> = std::iter::once(public_key.fingerprint())
+            .chain(
+                public_key
+                    .public_subkeys
+                    .iter()
+                    .map(|s| s.key.fingerprint()),
+            )
+            .map(|fingerprint| fingerprint.to_string())
+            .collect();
+        if !fingerprints.contains(&fingerprint) {
+            return Err(format!(
+                "{:?} is signed with key {}, not {}",
+                path,
+                public_key.fingerprint(),
+                fingerprint
+            )
+            .into());
+        }
+        public_key
+    };
+
+    // The signature may come from the primary key or a signing subkey
+    let digest = digest(&data)?;
+    let verified = signature
+        .verify(&public_key.primary_key, digest.as_bytes())
+        .is_ok()
+        || public_key
+            .public_subkeys
+            .iter()
+            .any(|subkey| signature.verify(&subkey.key, digest.as_bytes()).is_ok());
+    if !verified {
+        return Err(format!(
+            "Bad signature: {:?} was not signed with key {} or was modified after signing",
+            path,
+            public_key.fingerprint()
+        )
+        .into());
+    }
+
+    let user = public_key
+        .details
+        .users
+        .first()
+        .map(|user| String::from_utf8_lossy(user.id.id()).into_owned())
+        .unwrap_or_default();
+    Ok(format!("{} {}", public_key.fingerprint(), user)
+        .trim_end()
+        .to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pgp::composed::{KeyType, SecretKeyParamsBuilder};
+
+    /// Generate a passphrase-less signing key and write it ASCII-armored to `path`.
+    fn write_key(path: &Path, user_id: &str) -> SignedSecretKey {
+        let mut rng = rand::thread_rng();
+        let key = SecretKeyParamsBuilder::default()
+            .version(KeyVersion::V4)
+            .key_type(KeyType::Ed25519)
+            .can_certify(true)
+            .can_sign(true)
+            .primary_user_id(user_id.into())
+            .build()
+            .unwrap()
+            .generate(&mut rng)
+            .unwrap()
+            .sign(&mut rng, &Password::empty())
+            .unwrap();
+        fs::write(
+            path,
+            key.to_armored_string(ArmorOptions::default()).unwrap(),
+        )
+        .unwrap();
+        key
+    }
+
+    #[test]
+    fn sign_verify_round_trip() {
+        let dir = std::env::temp_dir().join(format!("signature-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let (key_path, other_path) = (dir.join("key.asc"), dir.join("other.asc"));
+        let key = write_key(&key_path, "Signer <signer@example.com>");
+        let other = write_key(&other_path, "Other <other@example.com>");
+        let public_path = dir.join("key.pub.asc");
+        let public_key = key.signed_public_key();
+        let armored = public_key.to_armored_string(ArmorOptions::default());
+        fs::write(&public_path, armored.unwrap()).unwrap();
+
+        let appimage = dir.join("app.AppImage");
+        let mut data = include_bytes!("apprun/runtime-x86_64").to_vec();
+        data.extend_from_slice(b"squashfs payload");
+        fs::write(&appimage, &data).unwrap();
+        let fingerprint = key.fingerprint().to_string();
+        let error = verify_appimage(&appimage, &fingerprint).unwrap_err();
+        assert!(error.to_string().ends_with("is not signed"), "{}", error);
+
+        sign_appimage(&appimage, key_path.to_str().unwrap()).unwrap();
+        let expected = format!("{} Signer <signer@example.com>", fingerprint);
+        let by_fingerprint = fingerprint.to_ascii_uppercase();
+        assert_eq!(
+            verify_appimage(&appimage, &by_fingerprint).unwrap(),
+            expected
+        );
+        let by_file = public_path.to_str().unwrap();
+        assert_eq!(verify_appimage(&appimage, by_file).unwrap(), expected);
+
+        // Another key neither matches the embedded key nor verifies the signature
+        let other_fingerprint = other.fingerprint().to_string();
+        let error = verify_appimage(&appimage, &other_fingerprint).unwrap_err();
+        assert!(
+            error.to_string().contains("is signed with key"),
+            "{}",
+            error
+        );
+        let other_public_path = dir.join("other.pub.asc");
+        let armored = other
+            .signed_public_key()
+            .to_armored_string(ArmorOptions::default());
+        fs::write(&other_public_path, armored.unwrap()).unwrap();
+        let error = verify_appimage(&appimage, other_public_path.to_str().unwrap()).unwrap_err();
+        assert!(error.to_string().starts_with("Bad signature"), "{}", error);
+
+        // A copy re-signed with another key carries a consistent but untrusted key
+        let mut signed = fs::read(&appimage).unwrap();
+        let resigned = dir.join("resigned.AppImage");
+        fs::write(&resigned, &signed).unwrap();
+        sign_appimage(&resigned, other_path.to_str().unwrap()).unwrap();
+        assert!(verify_appimage(&resigned, &other_fingerprint).is_ok());
+        assert!(verify_appimage(&resigned, &fingerprint).is_err());
+        assert!(verify_appimage(&resigned, by_file).is_err());
+
+        // Modifying the payload breaks the signature
+        let last = signed.len() - 1;
+        signed[last] ^= 1;
+        fs::write(&appimage, &signed).unwrap();
+        let error = verify_appimage(&appimage, &fingerprint).unwrap_err();
+        fs::remove_dir_all(&dir).unwrap();
+        assert!(error.to_string().starts_with("Bad signature"), "{}", error);
+    }
+}
@@ -1,24 +1,30 @@
+use crate::attributes::AttributeTable;
+use crate::compression::{Algorithm, Compression};
+use crate::reproducible;
 use backhand::{
-    FilesystemCompressor, FilesystemWriter, NodeHeader,
-    compression::{CompressionOptions, Compressor, Zstd},
+    CompressionExtra, ExtraXz, FilesystemCompressor, FilesystemWriter, NodeHeader,
+    compression::{CompressionOptions, Compressor, Gzip, Lz4, Zstd},
 };
 use std::{fs, path::Path};
 use walkdir::WalkDir;
 
-pub fn squashfs_build(app_dir: &str, output_name: &str) -> Result<(), Box<dyn std::error::Error>> {
+pub fn squashfs_build(
+    app_dir: &str,
+    output_name: &str,
+    attributes: &AttributeTable,
+    compression: Compression,
+) -> Result<(), Box<dyn std::error::Error>> {
     if !Path::new(app_dir).exists() {
         return Err(format!("Directory {} not found", app_dir).into());
     }
 
     let mut fs_writer = FilesystemWriter::default();
 
-    // Configure Zstd level 20
-    fs_writer.set_compressor(FilesystemCompressor::new(
-        Compressor::Zstd,
-        Some(CompressionOptions::Zstd(Zstd {
-            compression_level: 20,
-        })),
-    )?);
+    fs_writer.set_compressor(filesystem_compressor(compression)?);
+
+    if let Some(epoch) = reproducible::source_date_epoch() {
+        fs_writer.set_time(u32::try_from(epoch)?);
+    }
 
     // Set root permissions
     fs_writer.set_root_uid(0);
@@ -26,17 +32,23 @@ pub fn squashfs_build(app_dir: &str, output_name: &str) -> Result<(), Box<dyn st
     fs_writer.set_root_mode(0o755);
 
     println!(
-        "Converting {} to SquashFS (level 20, 755 permissions)...",
-        app_dir
+        "Converting {} to SquashFS ({:?}{})...",
+        app_dir,
+        compression.algorithm,
+        compression
+            .level
+            .map(|level| format!(" level {}", level))
+            .unwrap_or_default()
     );
 
-    // Metadata: root-owned, 755 permissions
-    let header = NodeHeader::new(0o755, 0, 0, 0);
-
-    for entry in WalkDir::new(app_dir).min_depth(1) {
+    for entry in WalkDir::new(app_dir).min_depth(1).sort_by_file_name() {
         let entry = entry?;
         let rel_path = entry.path().strip_prefix(app_dir)?;
 
+        // Metadata: owners and modes from the attribute table
+        let attrs = attributes.get(Path::new(app_dir), rel_path)?;
+        let header = NodeHeader::new(attrs.mode as u16, attrs.uid, attrs.gid, 0);
+
         if entry.file_type().is_dir() {
             fs_writer.push_dir(rel_path, header)?;
         } else if entry.file_type().is_file() {
@@ -51,3 +63,42 @@ pub fn squashfs_build(app_dir: &str, output_name: &str) -> Result<(), Box<dyn st
     println!("Created {}.squashfs successfully!", output_name);
     Ok(())
 }
+
+fn filesystem_compressor(
+    compression: Compression,
+) -> Result<FilesystemCompressor, Box<dyn std::error::Error>> {
+    let compressor = match compression.algorithm {
+        Algorithm::Zstd => FilesystemCompressor::new(
+            Compressor::Zstd,
+            Some(CompressionOptions::Zstd(Zstd {
+                compression_level: compression.level.unwrap_or(20),
+            })),
+        )?,
+        Algorithm::Gzip => FilesystemCompressor::new(
+            Compressor::Gzip,
+            Some(CompressionOptions::Gzip(Gzip {
+                compression_level: compression.level.unwrap_or(9),
+                window_size: 15,
+                strategies: 0,
+            })),
+        )?,
+        Algorithm::Xz => {
+            let mut compressor = FilesystemCompressor::new(Compressor::Xz, None)?;
+            if let Some(level) = compression.level {
+                let mut extra = ExtraXz::default();
+                extra.level(level)?;
+                compressor.extra(CompressionExtra::Xz(extra))?;
+            }
+            compressor
+        }
+        Algorithm::Lz4 => FilesystemCompressor::new(
+            Compressor::Lz4,
+            Some(CompressionOptions::Lz4(Lz4 {
+                version: 1,
+                flags: 0,
+            })),
+        )?,
+        _ => return Err("SquashFS images must be compressed".into()),
+    };
+    Ok(compressor)
+}
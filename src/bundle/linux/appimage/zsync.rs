@@ -0,0 +1,138 @@
+use md4::{Digest, Md4};
+use sha1::Sha1;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+/// Write the zsync control file for `path` to `<path>.zsync`, as `zsyncmake` does: a header
+/// followed by the rolling checksum and truncated MD4 of every block. `url` is where the
+/// AppImage is downloaded from, relative to the control file.
+pub fn write_control_file(path: &Path, url: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let length = std::fs::metadata(path)?.len();
+    let block_size: usize = if length < 100_000_000 { 2048 } else { 4096 };
+
+    // Checksum lengths chosen like zsyncmake: long enough to keep false matches unlikely
+    let len = length as f64;
+    let blocks = (length / block_size as u64) as f64;
+    let seq_matches = if length > block_size as u64 { 2 } else { 1 };
+    let rsum_bytes =
+        ((((len.ln() + (block_size as f64).ln()) / 2f64.ln() - 8.6) / seq_matches as f64 / 8.0)
+            .ceil() as usize)
+            .clamp(2, 4);
+    let checksum_bytes =
+        (((20.0 + (len.ln() + (1.0 + blocks).ln()) / 2f64.ln()) / seq_matches as f64 / 8.0).ceil()
+            as usize)
+            .max(((7.9 + (20.0 + (1.0 + blocks).ln() / 2f64.ln())) / 8.0) as usize)
+            .min(16);
+
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut sha1 = Sha1::new();
+    let mut sums = Vec::new();
+    let mut block = vec![0u8; block_size];
+    loop {
+        let mut filled = 0;
+        while filled < block_size {
+            let n = reader.read(&mut block[filled..])?;
+            if n == 0 {
+                break;
+            }
+            filled += n;
+        }
+        if filled == 0 {
+            break;
+        }
+        sha1.update(&block[..filled]);
+        // The last block is checksummed padded with zeros
+        block[filled..].fill(0);
+
+        let (a, b) = rsum(&block);
+        let rsum = [a.to_be_bytes(), b.to_be_bytes()].concat();
+        sums.extend_from_slice(&rsum[4 - rsum_bytes..]);
+        sums.extend_from_slice(&Md4::digest(&block)[..checksum_bytes]);
+        if filled < block_size {
+            break;
+        }
+    }
+
+    let file_name = path
+        .file_name()
+        .unwrap_or_default()
+        .to_string_lossy()
+        .into_owned();
+    let zsync_path = path.with_file_name(format!("{}.zsync", file_name));
+    let mut out = BufWriter::new(File::create(&zsync_path)?);
+    write!(
+        out,
+        "zsync: 0.6.2\nFilename: {}\nBlocksize: {}\nLength: {}\nHash-Lengths: {},{},{}\nURL: {}\nSHA-1: {}\n\n",
+        file_name,
+        block_size,
+        length,
+        seq_matches,
+        rsum_bytes,
+        checksum_bytes,
+        url,
+        hex::encode(sha1.finalize())
+    )?;
+    out.write_all(&sums)?;
+    out.flush()?;
+    println!("Created zsync file {:?}", zsync_path);
+    Ok(())
+}
+
+/// The rsync-style rolling checksum zsync uses, as its two 16-bit halves.
+fn rsum(block: &[u8]) -> (u16, u16) {
+    let mut a: u16 = 0;
+    let mut b: u16 = 0;
+    for (index, &byte) in block.iter().enumerate() {
+        a = a.wrapping_add(byte as u16);
+        b = b.wrapping_add(((block.len() - index) as u16).wrapping_mul(byte as u16));
+    }
+    (a, b)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rsum_matches_zsync() {
+        assert_eq!(rsum(&[]), (0, 0));
+        assert_eq!(rsum(&[1, 2, 3]), (6, 3 + 2 * 2 + 3));
+        // Both halves wrap at 16 bits
+        assert_eq!(rsum(&[0xff; 300]), (10964, 44450));
+    }
+
+    #[test]
+    fn control_file_layout() {
+        let dir = std::env::temp_dir().join(format!("zsync-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("app.AppImage");
+        let data: Vec<u8> = (0..5000u32).map(|i| (i * 7 % 251) as u8).collect();
+        std::fs::write(&path, &data).unwrap();
+
+        write_control_file(&path, "app.AppImage").unwrap();
+        let control = std::fs::read(dir.join("app.AppImage.zsync")).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let header_end = control.windows(2).position(|w| w == b"\n\n").unwrap() + 2;
+        let header = std::str::from_utf8(&control[..header_end]).unwrap();
+        assert_eq!(
+            header,
+            format!(
+                "zsync: 0.6.2\nFilename: app.AppImage\nBlocksize: 2048\nLength: 5000\n\
+                 Hash-Lengths: 2,2,3\nURL: app.AppImage\nSHA-1: {}\n\n",
+                hex::encode(Sha1::digest(&data))
+            )
+        );
+
+        // Three blocks of 2 rsum and 3 MD4 bytes; with 2 rsum bytes only its second half is kept
+        // and the last block is padded with zeros
+        let sums = &control[header_end..];
+        assert_eq!(sums.len(), 3 * 5);
+        let mut last = data[4096..].to_vec();
+        last.resize(2048, 0);
+        let (_, b) = rsum(&last);
+        assert_eq!(&sums[10..12], &b.to_be_bytes());
+        assert_eq!(&sums[12..15], &Md4::digest(&last)[..3]);
+    }
+}
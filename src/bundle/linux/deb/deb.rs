@@ -1,21 +1,80 @@
+use crate::arch;
+use crate::attributes::AttributeTable;
+use crate::autodeps;
 use crate::bundle::linux::alpm::pkg_info::get_installed_size;
 use crate::chmod::chmod_package;
-use crate::metadata;
-use flate2::Compression;
-use flate2::write::GzEncoder;
+use crate::compression::{self, Algorithm, Compression};
+use crate::debug;
+use crate::metadata::{self, Metadata};
+use crate::reproducible;
+use crate::scripts;
+use crate::strip;
+use crate::systemd;
+use crate::version::{self, VersionFormat};
 use md5::{Digest, Md5};
 use std::fs::{self, File};
-use std::io::{self, Read, Write};
+use std::io::{self, BufWriter, Read, Write};
 use std::path::{Path, PathBuf};
 use tar::Builder;
 
-pub fn deb_build() -> Result<(), Box<dyn std::error::Error>> {
-    let metadata = metadata::extract_metadata("metadata")?;
+pub fn deb_build(arch: &str, compression: Option<&str>) -> Result<(), Box<dyn std::error::Error>> {
+    let metadata = metadata::extract_metadata_for_arch("metadata", Some(arch))?;
+    version::validate(VersionFormat::Deb, &metadata)?;
+    // dpkg-deb defaults to xz; zstd needs dpkg 1.21.18 or newer
+    let compression = compression::resolve(
+        "deb",
+        compression,
+        &metadata.deb_compression,
+        Compression::new(Algorithm::Xz, None),
+        &[
+            Algorithm::Xz,
+            Algorithm::Zstd,
+            Algorithm::Gzip,
+            Algorithm::None,
+        ],
+    )?;
+
+    for package in metadata.split_packages() {
+        build_package(package, compression)?;
+    }
+    Ok(())
+}
+
+/// Stage and build one (possibly split) package.
+fn build_package(
+    metadata: &Metadata,
+    compression: Compression,
+) -> Result<(), Box<dyn std::error::Error>> {
     let deb_dir = format!("{}.deb", metadata.name);
-    let base_dir = PathBuf::from(&deb_dir);
 
     // Prepare files in temporary directory using chmod_package logic
-    chmod_package(&deb_dir, false)?;
+    let attributes = chmod_package(&deb_dir, metadata, false)?;
+    let debug_package = debug::split_debug_info(metadata, &deb_dir, "-dbgsym", ".deb")?;
+    strip::strip_package(&deb_dir, metadata)?;
+    let metadata = &autodeps::with_library_relations(metadata, &deb_dir, VersionFormat::Deb)?;
+
+    write_package(metadata, &deb_dir, &attributes, compression)?;
+    if let Some(debug) = debug_package {
+        write_package(
+            &debug.metadata,
+            &debug.pkg_dir,
+            &debug.attributes,
+            compression,
+        )?;
+    }
+    Ok(())
+}
+
+/// Add the control files to a staged package and assemble the .deb (or .ddeb for debug symbols).
+fn write_package(
+    metadata: &Metadata,
+    deb_dir: &str,
+    attributes: &AttributeTable,
+    compression: Compression,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let data_tar_name = format!("data.tar{}", compression.extension());
+    let control_tar_name = format!("control.tar{}", compression.extension());
+    let base_dir = PathBuf::from(deb_dir);
 
     // Create DEBIAN directory for control file
     let debian_dir = base_dir.join("DEBIAN");
@@ -25,18 +84,10 @@ pub fn deb_build() -> Result<(), Box<dyn std::error::Error>> {
     let control_path = debian_dir.join("control");
     let mut control_file = File::create(control_path)?;
 
-    let architecture = if metadata.arch.contains(&"x86_64".to_string()) {
-        "amd64"
-    } else {
-        "all"
-    };
+    let architecture = arch::lookup(&metadata.target_arch)?.deb;
 
     writeln!(control_file, "Package: {}", metadata.name)?;
-    writeln!(
-        control_file,
-        "Version: {}-{}",
-        metadata.version, metadata.release
-    )?;
+    writeln!(control_file, "Version: {}", version::full_version(metadata))?;
     writeln!(control_file, "Architecture: {}", architecture)?;
     writeln!(
         control_file,
@@ -57,49 +108,118 @@ pub fn deb_build() -> Result<(), Box<dyn std::error::Error>> {
     if !metadata.provides.is_empty() {
         writeln!(control_file, "Provides: {}", metadata.provides.join(", "))?;
     }
-    writeln!(control_file, "Section: utils")?;
-    writeln!(control_file, "Priority: optional")?;
+    if metadata.is_debug {
+        // Fields written by dh_strip for automatic -dbgsym packages
+        writeln!(control_file, "Section: debug")?;
+        writeln!(control_file, "Priority: optional")?;
+        writeln!(control_file, "Auto-Built-Package: debug-symbols")?;
+        writeln!(
+            control_file,
+            "Build-Ids: {}",
+            build_ids(&base_dir)?.join(" ")
+        )?;
+    } else {
+        writeln!(control_file, "Section: utils")?;
+        writeln!(control_file, "Priority: optional")?;
+    }
     writeln!(control_file, "Homepage: {}", metadata.url)?;
     writeln!(control_file, "Description: {}", metadata.description)?;
 
-    // Create postrm script
-    let postrm_path = debian_dir.join("postrm");
-    let mut postrm_file = File::create(&postrm_path)?;
-    write!(
-        postrm_file,
-        "#!/bin/sh\nset -e\nif [ \"$1\" = \"remove\" ] || [ \"$1\" = \"purge\" ]; then\n    apt-get autoremove -y 2>/dev/null || true\nfi\n"
-    )?;
-    // Make postrm executable (chmod 755)
-    #[cfg(unix)]
-    {
-        use std::os::unix::fs::PermissionsExt;
-        fs::set_permissions(&postrm_path, fs::Permissions::from_mode(0o755))?;
+    // Create maintainer scripts; dpkg passes the operation as the first argument
+    // and, on upgrade, postinst gets the previously configured version as the second
+    // Users are created before unpacking so dpkg can resolve the owners in data.tar
+    let mut control_names = vec!["control", "md5sums"];
+    let create_users = systemd::create_users_snippet(metadata)?;
+    let create_tmpfiles = systemd::create_tmpfiles_snippet(metadata)?;
+    let maintainer_scripts = [
+        (
+            "preinst",
+            scripts::dispatch(
+                true,
+                &[
+                    ("", &create_users),
+                    (r#"[ "$1" = "install" ]"#, &metadata.pre_install),
+                    (r#"[ "$1" = "upgrade" ]"#, &metadata.pre_upgrade),
+                ],
+            ),
+        ),
+        (
+            "postinst",
+            scripts::dispatch(
+                true,
+                &[
+                    (
+                        r#"[ "$1" = "configure" ] && [ -z "$2" ]"#,
+                        &metadata.post_install,
+                    ),
+                    (
+                        r#"[ "$1" = "configure" ] && [ -n "$2" ]"#,
+                        &metadata.post_upgrade,
+                    ),
+                    (r#"[ "$1" = "configure" ]"#, &create_tmpfiles),
+                    ("", &systemd::deb_postinst_snippet(metadata)),
+                ],
+            ),
+        ),
+        (
+            "prerm",
+            scripts::dispatch(
+                true,
+                &[
+                    ("", &systemd::deb_prerm_snippet(metadata)),
+                    (r#"[ "$1" = "remove" ]"#, &metadata.pre_remove),
+                ],
+            ),
+        ),
+        (
+            "postrm",
+            scripts::dispatch(
+                true,
+                &[
+                    (r#"[ "$1" = "remove" ]"#, &metadata.post_remove),
+                    ("", &systemd::deb_postrm_snippet(metadata)),
+                ],
+            ),
+        ),
+    ];
+    for (name, script) in maintainer_scripts {
+        let Some(script) = script else { continue };
+        let script_path = debian_dir.join(name);
+        fs::write(&script_path, script)?;
+        // Make the script executable (chmod 755)
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(&script_path, fs::Permissions::from_mode(0o755))?;
+        }
+        control_names.push(name);
     }
 
     // Create debian-binary
     let debian_binary_path = base_dir.join("debian-binary");
     let mut debian_binary = File::create(&debian_binary_path)?;
-    write!(debian_binary, "2.0\n")?;
+    writeln!(debian_binary, "2.0")?;
 
     // Collect data files and compute md5sums
     let mut md5sums_entries: Vec<(String, String)> = Vec::new();
-    let mut data_files: Vec<(PathBuf, PathBuf)> = Vec::new(); // (abs_path, rel_path)
+    let mut data_files: Vec<PathBuf> = Vec::new(); // rel_path
 
-    for entry in walkdir::WalkDir::new(&base_dir) {
+    for entry in walkdir::WalkDir::new(&base_dir).sort_by_file_name() {
         let entry = entry?;
         let path = entry.path().to_path_buf();
         let rel_path = path.strip_prefix(&base_dir)?.to_path_buf();
 
         if rel_path.starts_with("DEBIAN")
             || rel_path == Path::new("debian-binary")
-            || rel_path == Path::new("data.tar.gz")
-            || rel_path == Path::new("control.tar.gz")
+            || rel_path == Path::new(&data_tar_name)
+            || rel_path == Path::new(&control_tar_name)
             || rel_path.as_os_str().is_empty()
         {
             continue;
         }
 
-        if path.is_file() {
+        let file_type = entry.file_type();
+        if file_type.is_file() {
             // Compute MD5
             let mut file_data = Vec::new();
             File::open(&path)?.read_to_end(&mut file_data)?;
@@ -108,9 +228,10 @@ pub fn deb_build() -> Result<(), Box<dyn std::error::Error>> {
             let digest = hasher.finalize();
             let rel_str = rel_path.to_string_lossy().into_owned();
             md5sums_entries.push((format!("{:x}", digest), rel_str.clone()));
-            data_files.push((path, rel_path));
-        } else if path.is_dir() {
-            data_files.push((path, rel_path));
+            data_files.push(rel_path);
+        } else if file_type.is_dir() || file_type.is_symlink() {
+            // Symlinks are archived as links; dpkg does not list them in md5sums
+            data_files.push(rel_path);
         }
     }
 
@@ -121,34 +242,49 @@ pub fn deb_build() -> Result<(), Box<dyn std::error::Error>> {
         writeln!(md5sums_file, "{}  {}", hash, rel)?;
     }
 
-    // Create data.tar.gz
-    let data_tar_gz_path = base_dir.join("data.tar.gz");
-    let data_tar_gz = File::create(&data_tar_gz_path)?;
-    let enc = GzEncoder::new(data_tar_gz, Compression::default());
-    let mut tar = Builder::new(enc);
-
-    for (abs_path, rel_path) in &data_files {
-        if abs_path.is_file() {
-            tar.append_path_with_name(abs_path, rel_path)?;
-        } else if abs_path.is_dir() {
-            tar.append_dir(rel_path, abs_path)?;
+    // Create data.tar
+    let data_tar_path = base_dir.join(&data_tar_name);
+    let mut tar = Builder::new(compression.writer(BufWriter::new(File::create(&data_tar_path)?))?);
+
+    // Owners and modes come from the attribute table, not from the user running the build
+    for rel_path in &data_files {
+        attributes.append_to_tar(&mut tar, &base_dir, rel_path)?;
+    }
+    tar.into_inner()?.finish()?.flush()?;
+
+    // Create conffiles so dpkg preserves local edits to config files on upgrade
+    if !metadata.backup.is_empty() {
+        let mut conffiles = File::create(debian_dir.join("conffiles"))?;
+        for path in &metadata.backup {
+            writeln!(conffiles, "/{}", path.trim_start_matches('/'))?;
+        }
+        control_names.push("conffiles");
+    }
+
+    // With deb_shlibs, tell dpkg-shlibdeps of dependent packages which package and
+    // version provide the staged shared libraries
+    if metadata.deb_shlibs {
+        let shlibs: Vec<String> = autodeps::scan(deb_dir)?
+            .provided
+            .iter()
+            .filter_map(|(soname, _)| autodeps::deb_shlibs_line(soname, metadata))
+            .collect();
+        if !shlibs.is_empty() {
+            fs::write(debian_dir.join("shlibs"), shlibs.join("\n") + "\n")?;
+            control_names.push("shlibs");
         }
     }
-    tar.finish()?;
-    let enc = tar.into_inner()?;
-    enc.finish()?;
-
-    // Create control.tar.gz (includes control, md5sums, postrm)
-    let control_tar_gz_path = base_dir.join("control.tar.gz");
-    let control_tar_gz = File::create(&control_tar_gz_path)?;
-    let enc = GzEncoder::new(control_tar_gz, Compression::default());
-    let mut tar = Builder::new(enc);
-    tar.append_path_with_name(debian_dir.join("control"), "control")?;
-    tar.append_path_with_name(&md5sums_path, "md5sums")?;
-    tar.append_path_with_name(&postrm_path, "postrm")?;
-    tar.finish()?;
-    let enc = tar.into_inner()?;
-    enc.finish()?;
+
+    // Create control.tar (control, md5sums, maintainer scripts, conffiles, shlibs)
+    let control_tar_path = base_dir.join(&control_tar_name);
+    let mut tar =
+        Builder::new(compression.writer(BufWriter::new(File::create(&control_tar_path)?))?);
+    // Control files carry no DSL attributes, so they default to root ownership
+    let control_attributes = AttributeTable::default();
+    for name in control_names {
+        control_attributes.append_to_tar(&mut tar, &debian_dir, Path::new(name))?;
+    }
+    tar.into_inner()?.finish()?.flush()?;
 
     // Combine into .deb using ar
     let output_path = std::env::current_dir()?.join("output");
@@ -156,16 +292,20 @@ pub fn deb_build() -> Result<(), Box<dyn std::error::Error>> {
         fs::create_dir_all(&output_path)?;
     }
     let deb_file_path = output_path.join(format!(
-        "{}_{}-{}_{}.deb",
-        metadata.name, metadata.version, metadata.release, architecture
+        "{}_{}-{}_{}.{}",
+        metadata.name,
+        metadata.version,
+        metadata.release,
+        architecture,
+        if metadata.is_debug { "ddeb" } else { "deb" }
     ));
     let deb_file = File::create(&deb_file_path)?;
     let mut ar = ar::Builder::new(deb_file);
 
-    // Add files in order: debian-binary, control.tar.gz, data.tar.gz
+    // Add files in order: debian-binary, control.tar, data.tar
     add_file_to_ar(&mut ar, &debian_binary_path, "debian-binary")?;
-    add_file_to_ar(&mut ar, &control_tar_gz_path, "control.tar.gz")?;
-    add_file_to_ar(&mut ar, &data_tar_gz_path, "data.tar.gz")?;
+    add_file_to_ar(&mut ar, &control_tar_path, &control_tar_name)?;
+    add_file_to_ar(&mut ar, &data_tar_path, &data_tar_name)?;
 
     println!(".deb package created successfully!");
     println!("  Location: {}", deb_file_path.display());
@@ -176,9 +316,35 @@ pub fn deb_build() -> Result<(), Box<dyn std::error::Error>> {
     Ok(())
 }
 
+/// Build IDs of the debug files staged under `.build-id/`.
+fn build_ids(base_dir: &Path) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    let mut ids = Vec::new();
+    for entry in walkdir::WalkDir::new(base_dir.join(debug::BUILD_ID_DIR))
+        .min_depth(2)
+        .sort_by_file_name()
+    {
+        let entry = entry?;
+        let prefix = entry.path().parent().and_then(Path::file_name);
+        let rest = entry.path().file_stem();
+        if let (Some(prefix), Some(rest)) = (prefix, rest) {
+            ids.push(format!(
+                "{}{}",
+                prefix.to_string_lossy(),
+                rest.to_string_lossy()
+            ));
+        }
+    }
+    Ok(ids)
+}
+
+/// Add a member with normalized owner and mode, dated like dpkg-deb does: at
+/// `SOURCE_DATE_EPOCH` if set, otherwise at the time of the build.
 fn add_file_to_ar<W: Write>(ar: &mut ar::Builder<W>, path: &Path, name: &str) -> io::Result<()> {
     let mut file = File::open(path)?;
-    let metadata = file.metadata()?;
-    let header = ar::Header::from_metadata(name.as_bytes().to_vec(), &metadata);
+    let mut header = ar::Header::new(name.as_bytes().to_vec(), file.metadata()?.len());
+    header.set_mtime(reproducible::build_date());
+    header.set_uid(0);
+    header.set_gid(0);
+    header.set_mode(0o100644);
     ar.append(&header, &mut file)
 }
@@ -1,23 +1,84 @@
+use crate::arch;
+use crate::attributes::AttributeTable;
+use crate::autodeps;
 use crate::chmod::chmod_package;
-use crate::metadata;
+use crate::compression::{self, Algorithm, Compression};
+use crate::debug;
+use crate::metadata::{self, Metadata};
+use crate::reproducible;
+use crate::scripts;
+use crate::strip;
+use crate::systemd;
+use crate::version::{self, VersionFormat};
 use std::fs::{self, File};
 use std::path::PathBuf;
 
-pub fn rpm_build() -> Result<(), Box<dyn std::error::Error>> {
-    let metadata = metadata::extract_metadata("metadata")?;
+pub fn rpm_build(arch: &str, compression: Option<&str>) -> Result<(), Box<dyn std::error::Error>> {
+    let metadata = metadata::extract_metadata_for_arch("metadata", Some(arch))?;
+    version::validate(VersionFormat::Rpm, &metadata)?;
+
+    // Fedora and RHEL compress payloads with zstd level 19
+    let compression = compression::resolve(
+        "RPM",
+        compression,
+        &metadata.rpm_compression,
+        Compression::new(Algorithm::Zstd, Some(19)),
+        &[
+            Algorithm::Zstd,
+            Algorithm::Xz,
+            Algorithm::Gzip,
+            Algorithm::None,
+        ],
+    )?;
+
+    for package in metadata.split_packages() {
+        build_package(package, compression)?;
+    }
+    Ok(())
+}
+
+/// Stage and build one (possibly split) package.
+fn build_package(
+    metadata: &Metadata,
+    compression: Compression,
+) -> Result<(), Box<dyn std::error::Error>> {
     let rpm_dir = format!("{}.rpm", metadata.name);
-    let base_dir = PathBuf::from(&rpm_dir);
 
     // Prepare files in temporary directory
-    chmod_package(&rpm_dir, false)?;
+    let attributes = chmod_package(&rpm_dir, metadata, false)?;
+    let debug_package = debug::split_debug_info(metadata, &rpm_dir, "-debuginfo", ".rpm")?;
+    strip::strip_package(&rpm_dir, metadata)?;
+    let metadata = &autodeps::with_library_relations(metadata, &rpm_dir, VersionFormat::Rpm)?;
 
-    let architecture = if metadata.arch.contains(&"x86_64".to_string()) {
-        "x86_64"
-    } else {
-        "noarch"
-    };
+    write_package(metadata, &rpm_dir, &attributes, compression)?;
+    if let Some(debug) = debug_package {
+        write_package(
+            &debug.metadata,
+            &debug.pkg_dir,
+            &debug.attributes,
+            compression,
+        )?;
+    }
+    Ok(())
+}
+
+/// Build the .rpm for a staged package.
+fn write_package(
+    metadata: &Metadata,
+    rpm_dir: &str,
+    attributes: &AttributeTable,
+    compression: Compression,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let base_dir = PathBuf::from(rpm_dir);
 
-    let build_config = rpm::BuildConfig::v4().compression(rpm::CompressionType::Gzip);
+    let architecture = arch::lookup(&metadata.target_arch)?.rpm;
+
+    let build_config = rpm::BuildConfig::v4().compression(match compression.algorithm {
+        Algorithm::Zstd => rpm::CompressionWithLevel::Zstd(compression.level.unwrap_or(19) as i32),
+        Algorithm::Xz => rpm::CompressionWithLevel::Xz(compression.level.unwrap_or(6)),
+        Algorithm::Gzip => rpm::CompressionWithLevel::Gzip(compression.level.unwrap_or(9)),
+        _ => rpm::CompressionWithLevel::None,
+    });
 
     let mut builder = rpm::PackageBuilder::new(
         &metadata.name,
@@ -29,41 +90,107 @@ pub fn rpm_build() -> Result<(), Box<dyn std::error::Error>> {
     .using_config(build_config)
     .release(&metadata.release);
 
-    // Add dependencies
-    for dep in &metadata.rpm_depends {
-        builder = builder.requires(rpm::Dependency::any(dep));
+    if !metadata.epoch.is_empty() {
+        builder = builder.epoch(metadata.epoch.parse()?);
     }
 
-    // Add provides
-    for prov in &metadata.provides {
-        builder = builder.provides(rpm::Dependency::any(prov));
+    // Clamps the build time and file mtimes
+    if let Some(epoch) = reproducible::source_date_epoch() {
+        builder = builder.source_date(u32::try_from(epoch)?);
     }
 
-    // Add conflicts
-    for conf in &metadata.conflicts {
-        builder = builder.conflicts(rpm::Dependency::any(conf));
+    builder = add_relations(builder, metadata)?;
+
+    // Scriptlets get the number of installed instances after the transaction:
+    // 1 on install, 2 or more on upgrade, 0 when the package is removed
+    let create_users = systemd::create_users_snippet(metadata)?;
+    let create_tmpfiles = systemd::create_tmpfiles_snippet(metadata)?;
+    if let Some(script) = scripts::dispatch(
+        false,
+        &[
+            ("", &create_users),
+            (r#"[ "$1" -eq 1 ]"#, &metadata.pre_install),
+            (r#"[ "$1" -ge 2 ]"#, &metadata.pre_upgrade),
+        ],
+    ) {
+        builder = builder.pre_install_script(script);
+    }
+    if let Some(script) = scripts::dispatch(
+        false,
+        &[
+            (r#"[ "$1" -eq 1 ]"#, &metadata.post_install),
+            (r#"[ "$1" -ge 2 ]"#, &metadata.post_upgrade),
+            ("", &create_tmpfiles),
+            ("", &systemd::rpm_post_snippet(metadata)),
+        ],
+    ) {
+        builder = builder.post_install_script(script);
+    }
+    if let Some(script) = scripts::dispatch(
+        false,
+        &[
+            ("", &systemd::rpm_preun_snippet(metadata)),
+            (r#"[ "$1" -eq 0 ]"#, &metadata.pre_remove),
+        ],
+    ) {
+        builder = builder.pre_uninstall_script(script);
     }
+    if let Some(script) = scripts::dispatch(
+        false,
+        &[
+            (r#"[ "$1" -eq 0 ]"#, &metadata.post_remove),
+            ("", &systemd::rpm_postun_snippet(metadata)),
+        ],
+    ) {
+        builder = builder.post_uninstall_script(script);
+    }
+
+    let backup: Vec<String> = metadata
+        .backup
+        .iter()
+        .map(|path| format!("/{}", path.trim_start_matches('/')))
+        .collect();
 
-    // Add files
-    for entry in walkdir::WalkDir::new(&base_dir) {
+    // Add files; owners and modes come from the attribute table
+    for entry in walkdir::WalkDir::new(&base_dir)
+        .min_depth(1)
+        .sort_by_file_name()
+    {
         let entry = entry?;
         let path = entry.path();
-        if path.is_file() {
-            let rel_path = path.strip_prefix(&base_dir)?;
-            let target_path = format!("/{}", rel_path.to_string_lossy());
-
-            // Get mode and add file
-            #[cfg(unix)]
-            {
-                use std::os::unix::fs::MetadataExt;
-                let mode = fs::metadata(path)?.mode();
-                builder = builder
-                    .with_file(path, rpm::FileOptions::new(&target_path).mode(mode as i32))?;
-            }
-            #[cfg(not(unix))]
-            {
-                builder = builder.with_file(path, rpm::FileOptions::new(&target_path))?;
+        let rel_path = path.strip_prefix(&base_dir)?;
+        let target_path = format!("/{}", rel_path.to_string_lossy());
+        let file_type = entry.file_type();
+        let attrs = attributes.get(&base_dir, rel_path)?;
+        let mut options = rpm::FileOptions::new(&target_path)
+            .user(&attrs.user)
+            .group(&attrs.group);
+        // %config(noreplace): keep local edits, install the new version as .rpmnew
+        if backup.contains(&target_path) {
+            options = options.is_config_noreplace();
+        }
+        let permissions = attrs.mode as u16;
+
+        if file_type.is_symlink() {
+            let link_target = fs::read_link(path)?;
+            builder = builder.with_file_contents(
+                Vec::new(),
+                options
+                    .symlink(link_target.to_string_lossy())
+                    .mode(rpm::FileMode::symbolic_link(permissions)),
+            )?;
+        } else if file_type.is_dir() {
+            // Only empty or explicitly created directories (e.g. from `mkdir`) are owned by the
+            // package; the others are implied by the files they contain and usually belong to
+            // the filesystem package
+            if attributes.contains(rel_path) || fs::read_dir(path)?.next().is_none() {
+                builder = builder.with_file_contents(
+                    Vec::new(),
+                    options.mode(rpm::FileMode::dir(permissions)),
+                )?;
             }
+        } else if file_type.is_file() {
+            builder = builder.with_file(path, options.mode(rpm::FileMode::regular(permissions)))?;
         }
     }
 
@@ -93,3 +220,102 @@ pub fn rpm_build() -> Result<(), Box<dyn std::error::Error>> {
 
     Ok(())
 }
+
+/// Add the requires, provides and conflicts of `metadata` to `builder`.
+fn add_relations(
+    mut builder: rpm::PackageBuilder,
+    metadata: &Metadata,
+) -> Result<rpm::PackageBuilder, Box<dyn std::error::Error>> {
+    // Add dependencies
+    for dep in &metadata.rpm_depends {
+        builder = builder.requires(dependency(dep)?);
+    }
+
+    // Add provides
+    for prov in &metadata.provides {
+        builder = builder.provides(dependency(prov)?);
+    }
+
+    // Add conflicts
+    for conf in &metadata.conflicts {
+        builder = builder.conflicts(dependency(conf)?);
+    }
+
+    Ok(builder)
+}
+
+/// Parse `name` or `name OP [epoch:]version[-release]` into a dependency. Rich dependencies
+/// such as `(foo or bar)` are passed through as a name.
+fn dependency(spec: &str) -> Result<rpm::Dependency, Box<dyn std::error::Error>> {
+    if spec.starts_with('(') {
+        return Ok(rpm::Dependency::any(spec));
+    }
+    match spec.split_whitespace().collect::<Vec<_>>().as_slice() {
+        [name] => Ok(rpm::Dependency::any(*name)),
+        [name, op, evr] => Ok(match *op {
+            "=" | "==" => rpm::Dependency::eq(*name, *evr),
+            "<" => rpm::Dependency::less(*name, *evr),
+            "<=" => rpm::Dependency::less_eq(*name, *evr),
+            ">" => rpm::Dependency::greater(*name, *evr),
+            ">=" => rpm::Dependency::greater_eq(*name, *evr),
+            _ => return Err(format!("Unknown operator '{}' in dependency '{}'", op, spec).into()),
+        }),
+        _ => Err(format!(
+            "Invalid dependency '{}', expected 'name' or 'name OP version'",
+            spec
+        )
+        .into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn requires_are_versioned() {
+        let metadata = Metadata {
+            name: "twincan-debuginfo".to_string(),
+            rpm_depends: vec![
+                "twincan = 2:1.0.0-1".to_string(),
+                "libc.so.6()(64bit)".to_string(),
+                "systemd >= 254".to_string(),
+                "(foo or bar)".to_string(),
+            ],
+            conflicts: vec!["twincan-legacy < 1.0".to_string()],
+            ..Default::default()
+        };
+        let builder =
+            rpm::PackageBuilder::new(&metadata.name, "1.0.0", "MIT", "x86_64", "Debug info");
+        let package = add_relations(builder, &metadata).unwrap().build().unwrap();
+
+        let requires = package.metadata.get_requires().unwrap();
+        for expected in [
+            rpm::Dependency::eq("twincan", "2:1.0.0-1"),
+            rpm::Dependency::any("libc.so.6()(64bit)"),
+            rpm::Dependency::greater_eq("systemd", "254"),
+            rpm::Dependency::any("(foo or bar)"),
+        ] {
+            assert!(
+                requires.contains(&expected),
+                "{:?} in {:?}",
+                expected,
+                requires
+            );
+        }
+        let conflicts = package.metadata.get_conflicts().unwrap();
+        assert_eq!(conflicts, [rpm::Dependency::less("twincan-legacy", "1.0")]);
+    }
+
+    #[test]
+    fn dependency_errors() {
+        for (spec, message) in [
+            ("twincan ~ 1.0", "Unknown operator '~'"),
+            ("twincan =", "Invalid dependency"),
+            ("twincan = 1.0 extra", "Invalid dependency"),
+        ] {
+            let error = dependency(spec).unwrap_err().to_string();
+            assert!(error.contains(message), "{}: {}", spec, error);
+        }
+    }
+}
@@ -1,8 +1,7 @@
 use super::metadata::Metadata;
-use blake2::{Blake2b512, Digest};
-use md5::Md5;
+use md5::{Digest, Md5};
 use sha1::Sha1;
-use sha2::{Sha224, Sha256, Sha384, Sha512};
+use sha2::{Sha256, Sha512};
 use std::fs::File;
 use std::io::{self, Read};
 
@@ -30,22 +29,10 @@ pub fn sha1_hash(path: &str) -> io::Result<String> {
     compute_hash::<Sha1>(path)
 }
 
-pub fn blake2b512_hash(path: &str) -> io::Result<String> {
-    compute_hash::<Blake2b512>(path)
-}
-
-pub fn sha224_hash(path: &str) -> io::Result<String> {
-    compute_hash::<Sha224>(path)
-}
-
 pub fn sha256_hash(path: &str) -> io::Result<String> {
     compute_hash::<Sha256>(path)
 }
 
-pub fn sha384_hash(path: &str) -> io::Result<String> {
-    compute_hash::<Sha384>(path)
-}
-
 pub fn sha512_hash(path: &str) -> io::Result<String> {
     compute_hash::<Sha512>(path)
 }
@@ -1,11 +1,20 @@
-use super::metadata;
+use super::appstream;
+use super::attributes::{AttributeTable, FileAttributes, Owners};
+use super::desktop;
+use super::icon;
+use super::metadata::Metadata;
+use super::systemd;
 use std::fs;
 use std::os::unix::fs::PermissionsExt;
-use std::path::{Path, PathBuf};
+use std::path::{Component, Path, PathBuf};
 
-pub fn chmod_package(pkg_dir: &str, is_appimage: bool) -> Result<(), Box<dyn std::error::Error>> {
-    let metadata_path = "metadata";
-    let metadata = metadata::extract_metadata(metadata_path)?;
+/// Stage the package into `pkg_dir` and return the ownership and modes recorded by the DSL,
+/// which the builders apply instead of the on-disk owners.
+pub fn chmod_package(
+    pkg_dir: &str,
+    metadata: &Metadata,
+    is_appimage: bool,
+) -> Result<AttributeTable, Box<dyn std::error::Error>> {
     println!("Building Package: {}", metadata.name);
 
     // Use a 'bundle' directory to avoid conflicts with source files/directories of the same name
@@ -16,63 +25,547 @@ pub fn chmod_package(pkg_dir: &str, is_appimage: bool) -> Result<(), Box<dyn std
         fs::remove_dir_all(&base_dir)?;
     }
     fs::create_dir_all(&base_dir)?;
+    let mut attributes = AttributeTable::default();
+    let owners = Owners::from_metadata(metadata)?;
 
     // -------- Process instructions --------
-    for line in &metadata.package_instructions {
-        process_install_line(line, &base_dir, &metadata.name, false)?;
+    for (line_no, line) in &metadata.package_instructions {
+        process_line(
+            line,
+            *line_no,
+            &base_dir,
+            &metadata.pkgbase,
+            false,
+            &owners,
+            &mut attributes,
+        )?;
     }
 
     if is_appimage {
-        for line in &metadata.appimage_icon_instructions {
-            process_install_line(line, &base_dir, &metadata.name, true)?;
+        for (line_no, line) in &metadata.appimage_icon_instructions {
+            process_line(
+                line,
+                *line_no,
+                &base_dir,
+                &metadata.pkgbase,
+                true,
+                &owners,
+                &mut attributes,
+            )?;
         }
-        for line in &metadata.appimage_desktop_instructions {
-            process_install_line(line, &base_dir, &metadata.name, true)?;
+        for (line_no, line) in &metadata.appimage_desktop_instructions {
+            process_line(
+                line,
+                *line_no,
+                &base_dir,
+                &metadata.pkgbase,
+                true,
+                &owners,
+                &mut attributes,
+            )?;
+        }
+    }
+
+    if !is_appimage {
+        stage_systemd(&base_dir, metadata, &owners, &mut attributes)?;
+    }
+
+    // The generated desktop entry is installed for menus and, in an AppDir, also at the root
+    if let Some(entry) = desktop::generate(metadata, is_appimage)? {
+        let file_name = desktop::file_name(metadata);
+        let mut paths = vec![format!("/usr/share/applications/{}", file_name)];
+        if is_appimage {
+            paths.push(format!("/{}", file_name));
+        }
+        for path in paths {
+            write_generated(&base_dir, &path, entry.as_bytes(), &mut attributes)?;
+        }
+    }
+
+    if let Some((id, metainfo)) = appstream::generate(metadata)? {
+        let path = appstream::metainfo_path(&id);
+        write_generated(&base_dir, &path, metainfo.as_bytes(), &mut attributes)?;
+    }
+
+    if let Some(icon) = icon::render(metadata)? {
+        let mut files = icon.theme_files(metadata);
+        if is_appimage {
+            files.extend(icon.appdir_files(metadata));
+        }
+        for (path, contents) in files {
+            write_generated(&base_dir, &path, contents, &mut attributes)?;
+        }
+    }
+
+    // Config files must have been staged by the instructions above
+    for path in &metadata.backup {
+        let staged = staged_path(&base_dir, path)?;
+        if !staged.is_file() || staged.is_symlink() {
+            return Err(format!("Backup file {} was not installed as a regular file", path).into());
         }
     }
 
     println!("Build Complete at {:?}", base_dir);
+    Ok(attributes)
+}
+
+/// A word from a DSL line. `quoted` is set when any part of it was quoted, so that keywords
+/// like `to` and `as` are only recognised when written bare.
+struct Token {
+    text: String,
+    quoted: bool,
+}
+
+impl Token {
+    fn is_keyword(&self, keyword: &str) -> bool {
+        !self.quoted && self.text == keyword
+    }
+}
+
+/// Split a line into words using shell-style quoting: single quotes are literal, double quotes
+/// allow `\"` and `\\` escapes, and a backslash outside quotes escapes the next character.
+fn tokenize(line: &str) -> Result<Vec<Token>, Box<dyn std::error::Error>> {
+    let mut tokens = Vec::new();
+    let mut chars = line.chars().peekable();
+
+    loop {
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+        if chars.peek().is_none() {
+            break;
+        }
+
+        let mut text = String::new();
+        let mut quoted = false;
+        while let Some(c) = chars.next_if(|c| !c.is_whitespace()) {
+            match c {
+                '\'' => {
+                    quoted = true;
+                    loop {
+                        match chars.next() {
+                            Some('\'') => break,
+                            Some(c) => text.push(c),
+                            None => return Err("Unterminated single quote".into()),
+                        }
+                    }
+                }
+                '"' => {
+                    quoted = true;
+                    loop {
+                        match chars.next() {
+                            Some('"') => break,
+                            Some('\\') => match chars.next() {
+                                Some(c @ ('"' | '\\')) => text.push(c),
+                                Some(c) => {
+                                    text.push('\\');
+                                    text.push(c);
+                                }
+                                None => return Err("Unterminated double quote".into()),
+                            },
+                            Some(c) => text.push(c),
+                            None => return Err("Unterminated double quote".into()),
+                        }
+                    }
+                }
+                '\\' => {
+                    quoted = true;
+                    text.push(chars.next().ok_or("Trailing backslash")?);
+                }
+                c => text.push(c),
+            }
+        }
+        tokens.push(Token { text, quoted });
+    }
+
+    Ok(tokens)
+}
+
+/// Split a line into words with the quoting rules of [`tokenize`].
+pub fn split_words(line: &str) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    Ok(tokenize(line)?.into_iter().map(|t| t.text).collect())
+}
+
+/// Install the `systemd_units` and write the sysusers.d/tmpfiles.d files for the `users:` block.
+fn stage_systemd(
+    base_dir: &Path,
+    metadata: &Metadata,
+    owners: &Owners,
+    attributes: &mut AttributeTable,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut ctx = Staging {
+        base_dir,
+        owners,
+        attributes,
+    };
+    for unit in &metadata.systemd_units {
+        let file_name = Path::new(unit)
+            .file_name()
+            .ok_or_else(|| format!("systemd_units: no file name: {}", unit))?;
+        let dest = format!("{}/{}", systemd::UNIT_DIR, file_name.to_string_lossy());
+        let full_dest = staged_path(base_dir, &dest)?;
+        source_path(Path::new(&metadata.pkgbase), unit)
+            .and_then(|source| {
+                install_file(&mut ctx, &source, &full_dest, &FileAttributes::root(0o644))
+            })
+            .map_err(|e| format!("systemd_units: {}", e))?;
+    }
+
+    let (sysusers, tmpfiles) = systemd::users_config(metadata)?;
+    for (path, contents) in [
+        (systemd::sysusers_path(metadata), sysusers),
+        (systemd::tmpfiles_path(metadata), tmpfiles),
+    ] {
+        if !contents.is_empty() {
+            write_generated(base_dir, &path, contents.as_bytes(), ctx.attributes)?;
+        }
+    }
+
     Ok(())
 }
 
-fn process_install_line(
+/// Write a file generated from the metadata to `path` in the package, owned by root with mode
+/// 0644.
+fn write_generated(
+    base_dir: &Path,
+    path: &str,
+    contents: &[u8],
+    attributes: &mut AttributeTable,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let full_path = staged_path(base_dir, path)?;
+    if let Some(parent) = full_path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(&full_path, contents)?;
+    fs::set_permissions(&full_path, fs::Permissions::from_mode(0o644))?;
+    if let Ok(rel_path) = full_path.strip_prefix(base_dir) {
+        attributes.insert(rel_path, FileAttributes::root(0o644));
+    }
+    Ok(())
+}
+
+/// Map an absolute package path onto the staging directory. `..` is resolved lexically and
+/// may not climb above the staging root, and no existing ancestor inside the staging
+/// directory may be a symlink, so writes can never land outside `base_dir`.
+fn staged_path(base_dir: &Path, dest: &str) -> Result<PathBuf, Box<dyn std::error::Error>> {
+    let mut components: Vec<&std::ffi::OsStr> = Vec::new();
+    for component in Path::new(dest).components() {
+        match component {
+            Component::Normal(part) => components.push(part),
+            Component::ParentDir => {
+                if components.pop().is_none() {
+                    return Err(format!("Destination escapes the staging root: {}", dest).into());
+                }
+            }
+            Component::RootDir | Component::CurDir | Component::Prefix(_) => {}
+        }
+    }
+
+    let mut full_path = base_dir.to_path_buf();
+    for (idx, part) in components.iter().enumerate() {
+        full_path.push(part);
+        // The final component may itself be a symlink that gets replaced, but nothing may be
+        // written through one
+        if idx + 1 < components.len() && full_path.is_symlink() {
+            return Err(format!("Destination passes through a symlink: {}", dest).into());
+        }
+    }
+
+    Ok(full_path)
+}
+
+/// Map a source path onto the source directory. Like [`staged_path`], `..` is resolved
+/// lexically and may not climb above `source_dir`, and absolute paths are rejected, so that
+/// only files of the package's own sources can be installed.
+fn source_path(source_dir: &Path, source: &str) -> Result<PathBuf, Box<dyn std::error::Error>> {
+    let mut components: Vec<&std::ffi::OsStr> = Vec::new();
+    for component in Path::new(source).components() {
+        match component {
+            Component::Normal(part) => components.push(part),
+            Component::ParentDir => {
+                if components.pop().is_none() {
+                    return Err(format!("Source escapes the source directory: {}", source).into());
+                }
+            }
+            Component::RootDir | Component::Prefix(_) => {
+                return Err(format!(
+                    "Source must be relative to the source directory: {}",
+                    source
+                )
+                .into());
+            }
+            Component::CurDir => {}
+        }
+    }
+    Ok(components
+        .iter()
+        .fold(source_dir.to_path_buf(), |path, part| path.join(part)))
+}
+
+/// Parse an octal mode such as `755` or `0644`.
+fn parse_mode(mode_str: &str) -> Result<u32, Box<dyn std::error::Error>> {
+    let mode = u32::from_str_radix(mode_str, 8)
+        .map_err(|_| format!("Invalid mode '{}', expected octal digits", mode_str))?;
+    if mode > 0o7777 {
+        return Err(format!("Invalid mode '{}', out of range", mode_str).into());
+    }
+    Ok(mode)
+}
+
+fn process_line(
     line: &str,
+    line_no: usize,
     base_dir: &Path,
     metadata_name: &str,
     default_to_root: bool,
+    owners: &Owners,
+    attributes: &mut AttributeTable,
 ) -> Result<(), Box<dyn std::error::Error>> {
     let trimmed = line.trim();
-    if !trimmed.starts_with("install") {
-        return Ok(());
+
+    let result = tokenize(trimmed).and_then(|tokens| {
+        let directive = tokens.first().map(|t| t.text.as_str()).unwrap_or("");
+        let args = tokens.get(1..).unwrap_or(&[]);
+        let ctx = Staging {
+            base_dir,
+            owners,
+            attributes,
+        };
+        match directive {
+            "install" => process_install_line(args, ctx, metadata_name, default_to_root),
+            "symlink" => process_symlink_line(args, ctx),
+            "mkdir" => process_mkdir_line(args, ctx),
+            _ => Err(format!("Unknown directive '{}'", directive).into()),
+        }
+    });
+
+    result.map_err(|e| format!("line {}: {}: {}", line_no, e, trimmed).into())
+}
+
+/// The staging directory together with the attribute table recording what goes into it.
+struct Staging<'a> {
+    base_dir: &'a Path,
+    owners: &'a Owners,
+    attributes: &'a mut AttributeTable,
+}
+
+impl Staging<'_> {
+    /// Record `attrs` for a path returned by [`staged_path`].
+    fn record(&mut self, full_path: &Path, attrs: FileAttributes) {
+        if let Ok(rel_path) = full_path.strip_prefix(self.base_dir) {
+            self.attributes.insert(rel_path, attrs);
+        }
     }
+}
 
-    // Example: install -m755 "twincan" to "/usr/bin/"
-    let parts: Vec<&str> = trimmed.split_whitespace().collect();
-    if parts.len() < 3 {
-        return Ok(());
+/// Options accepted by `install` and `mkdir`, modelled on coreutils `install`.
+#[derive(Default)]
+struct Options {
+    mode: Option<String>,
+    owner: Option<String>,
+    group: Option<String>,
+    create_leading: bool,
+    target_dir: Option<String>,
+}
+
+impl Options {
+    /// Mode, owner and group to record, defaulting to `default_mode` and `root:root`. Without
+    /// `-g`, the group is the primary group of the owner.
+    fn attributes(
+        &self,
+        default_mode: &str,
+        owners: &Owners,
+    ) -> Result<FileAttributes, Box<dyn std::error::Error>> {
+        let mode_str = self.mode.as_deref().unwrap_or(default_mode);
+        let (user, uid) = owners.user(self.owner.as_deref().unwrap_or("root"))?;
+        let group_spec = match &self.group {
+            Some(group) => group.as_str(),
+            None => owners.primary_group(&user),
+        };
+        let (group, gid) = owners.group(group_spec)?;
+        let attrs = FileAttributes {
+            mode: parse_mode(mode_str)?,
+            user,
+            uid,
+            group,
+            gid,
+        };
+        Ok(attrs)
     }
+}
 
-    // Mode
-    let mut current_idx = 1;
-    let mode_str = if parts[current_idx].starts_with("-m") {
-        let m = parts[current_idx].trim_start_matches("-m");
-        current_idx += 1;
-        m
-    } else {
-        "644" // Default mode if not specified
+/// Parse leading options and return the remaining operands. `-m MODE`, `-o OWNER` and
+/// `-g GROUP` are always accepted; `flags` lists the directive-specific ones (`D` for
+/// `-D`, `t` for `-t DIR`, `p` for a no-op `-p`). Short options may be bundled (`-Dm755`).
+fn parse_options<'t>(
+    args: &'t [Token],
+    flags: &str,
+) -> Result<(Options, &'t [Token]), Box<dyn std::error::Error>> {
+    let mut options = Options::default();
+    let mut idx = 0;
+
+    while let Some(arg) = args.get(idx) {
+        if arg.quoted || !arg.text.starts_with('-') || arg.text == "-" {
+            break;
+        }
+        idx += 1;
+
+        if arg.text == "--" {
+            break;
+        } else if let Some((long, value)) = arg.text.split_once('=') {
+            let value = Some(value.to_string());
+            match long {
+                "--mode" => options.mode = value,
+                "--owner" => options.owner = value,
+                "--group" => options.group = value,
+                "--target-directory" if flags.contains('t') => options.target_dir = value,
+                _ => return Err(format!("Unknown option '{}'", long).into()),
+            }
+        } else if arg.text.starts_with("--") {
+            return Err(format!("Unknown option '{}'", arg.text).into());
+        } else {
+            // `m`, `o`, `g` and `t` take the rest of the word or the next word
+            let bundle = &arg.text[1..];
+            for (pos, flag) in bundle.char_indices() {
+                let slot = match flag {
+                    'D' | 'p' if flags.contains(flag) => {
+                        options.create_leading |= flag == 'D';
+                        continue;
+                    }
+                    'm' => &mut options.mode,
+                    'o' => &mut options.owner,
+                    'g' => &mut options.group,
+                    't' if flags.contains('t') => &mut options.target_dir,
+                    _ => return Err(format!("Unknown option '-{}'", flag).into()),
+                };
+                let rest = &bundle[pos + 1..];
+                *slot = Some(if !rest.is_empty() {
+                    rest.to_string()
+                } else {
+                    idx += 1;
+                    args.get(idx - 1)
+                        .map(|t| t.text.clone())
+                        .ok_or_else(|| format!("Option '-{}' requires a value", flag))?
+                });
+                break;
+            }
+        }
+    }
+
+    Ok((options, &args[idx..]))
+}
+
+fn process_install_line(
+    args: &[Token],
+    mut ctx: Staging,
+    metadata_name: &str,
+    default_to_root: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    // Examples:
+    //   install -m755 "twincan" to "/usr/bin/"           (into a directory)
+    //   install -m755 "twincan" as "/usr/bin/twincan"    (exact file path)
+    //   install -Dm644 "twincan.conf" "/etc/twincan.conf" (coreutils form, -D creates parents)
+    //   install -m644 -t "/usr/share/doc/twincan/" "README" "NEWS"
+    //   install -o root -g games -m 2755 "twincan-score" to "/usr/bin/"
+    let (options, operands) = parse_options(args, "Dt")?;
+    // Default mode if not specified
+    let attrs = options.attributes("644", ctx.owners)?;
+    let base_dir = ctx.base_dir;
+
+    // Each source is paired with its exact destination inside the staging directory
+    let mut installs: Vec<(String, PathBuf)> = Vec::new();
+    let into_dir = |dir: &str, sources: &[Token], create: bool, installs: &mut Vec<_>| {
+        let full_dir = staged_path(base_dir, dir)?;
+        if full_dir.is_symlink() {
+            return Err(format!("Target directory is a symlink: {}", dir).into());
+        }
+        if create {
+            fs::create_dir_all(&full_dir)?;
+        } else if !full_dir.is_dir() {
+            return Err(format!("Target directory does not exist: {} (use -D)", dir).into());
+        }
+        for source in sources {
+            let file_name = Path::new(&source.text)
+                .file_name()
+                .ok_or_else(|| format!("Source has no file name: {}", source.text))?;
+            installs.push((source.text.clone(), full_dir.join(file_name)));
+        }
+        Ok::<(), Box<dyn std::error::Error>>(())
     };
-    let mode = u32::from_str_radix(mode_str, 8)?;
 
-    // Source file
-    if current_idx >= parts.len() {
-        return Ok(());
+    let keyword_pos = operands
+        .iter()
+        .position(|t| t.is_keyword("to") || t.is_keyword("as"));
+
+    if let Some(dir) = &options.target_dir {
+        if keyword_pos.is_some() {
+            return Err("'-t' cannot be combined with 'to' or 'as'".into());
+        }
+        if operands.is_empty() {
+            return Err("Missing source file".into());
+        }
+        into_dir(dir, operands, options.create_leading, &mut installs)?;
+    } else if let Some(pos) = keyword_pos {
+        let (sources, rest) = operands.split_at(pos);
+        if sources.is_empty() {
+            return Err("Missing source file".into());
+        }
+        let [keyword, dest] = rest else {
+            return Err(
+                format!("Expected exactly one destination after '{}'", rest[0].text).into(),
+            );
+        };
+
+        if keyword.text == "to" {
+            // `to` always names a directory, whether or not it ends in '/'
+            into_dir(&dest.text, sources, true, &mut installs)?;
+        } else {
+            let [source] = sources else {
+                return Err("'as' takes exactly one source file".into());
+            };
+            installs.push((source.text.clone(), staged_path(base_dir, &dest.text)?));
+        }
+    } else {
+        match operands {
+            [] => return Err("Missing source file".into()),
+            [_] if default_to_root => into_dir("/", operands, true, &mut installs)?,
+            [_] => return Err("Missing destination ('to', 'as' or a second operand)".into()),
+            [source, dest] => {
+                // coreutils semantics: DEST is a file unless it is an existing directory
+                let full_dest = staged_path(base_dir, &dest.text)?;
+                if full_dest.is_dir() {
+                    into_dir(&dest.text, &operands[..1], false, &mut installs)?;
+                } else {
+                    let parent_exists = full_dest.parent().is_some_and(|p| p.is_dir());
+                    if !options.create_leading && !parent_exists {
+                        return Err(
+                            format!("Parent of {} does not exist (use -D)", dest.text).into()
+                        );
+                    }
+                    installs.push((source.text.clone(), full_dest));
+                }
+            }
+            [sources @ .., dest] => {
+                into_dir(&dest.text, sources, options.create_leading, &mut installs)?
+            }
+        }
     }
-    let source_file_name = parts[current_idx].trim_matches('"').to_string();
-    current_idx += 1;
 
     let source_dir = Path::new(metadata_name);
-    let source_path = source_dir.join(&source_file_name);
+    for (source_file_name, full_dest) in installs {
+        let source = source_path(source_dir, &source_file_name)?;
+        install_file(&mut ctx, &source, &full_dest, &attrs)?;
+    }
 
+    Ok(())
+}
+
+/// Copy `source_path` to `full_dest`, a path returned by [`staged_path`], with the mode of
+/// `attrs` and record `attrs` for it.
+fn install_file(
+    ctx: &mut Staging,
+    source_path: &Path,
+    full_dest: &Path,
+    attrs: &FileAttributes,
+) -> Result<(), Box<dyn std::error::Error>> {
     if !source_path.exists() {
         return Err(format!("Source not found: {:?}", source_path).into());
     }
@@ -81,32 +574,9 @@ fn process_install_line(
         return Err(format!("Source is a directory, not a file: {:?}", source_path).into());
     }
 
-    // Destination
-    let dest_path_str = if current_idx < parts.len() && parts[current_idx] == "to" {
-        current_idx += 1;
-        if current_idx < parts.len() {
-            parts[current_idx].trim_matches('"').to_string()
-        } else {
-            "/".to_string()
-        }
-    } else if default_to_root {
-        "/".to_string()
-    } else {
-        return Ok(()); // Skip if no destination and not defaulting to root
-    };
-
-    let dest_path_trimmed = dest_path_str.trim_start_matches('/');
-    let mut full_dest = base_dir.join(dest_path_trimmed);
-
-    // If destination is directory → append filename
-    if dest_path_str.ends_with('/') || !Path::new(&dest_path_str).extension().is_some() {
-        if let Some(file_name) = Path::new(&source_file_name).file_name() {
-            full_dest = full_dest.join(file_name);
-        }
-    }
-
     println!("Installing:");
-    println!("  Mode: {}", mode_str);
+    println!("  Mode: {:o}", attrs.mode);
+    println!("  Owner: {}:{}", attrs.user, attrs.group);
     println!("  Source: {:?}", source_path);
     println!("  Dest: {:?}", full_dest);
 
@@ -115,13 +585,395 @@ fn process_install_line(
         fs::create_dir_all(parent)?;
     }
 
+    // Replace rather than write through anything already staged at the destination
+    if full_dest.is_symlink() {
+        fs::remove_file(full_dest)?;
+    }
+
     // Copy
-    fs::copy(&source_path, &full_dest)?;
+    fs::copy(source_path, full_dest)?;
+
+    // Set permissions; ownership only lives in the attribute table
+    fs::set_permissions(full_dest, fs::Permissions::from_mode(attrs.mode))?;
+    ctx.record(full_dest, attrs.clone());
+    Ok(())
+}
+
+fn process_symlink_line(
+    args: &[Token],
+    mut ctx: Staging,
+) -> Result<(), Box<dyn std::error::Error>> {
+    // Example: symlink "/opt/twincan/bin/twincan" to "/usr/bin/twincan"
+    let [target, to, link] = args else {
+        return Err("Expected: symlink \"<target>\" to \"<link>\"".into());
+    };
+    if !to.is_keyword("to") {
+        return Err("Expected: symlink \"<target>\" to \"<link>\"".into());
+    }
+
+    // The target is stored verbatim; only the link itself must live inside the staging root
+    let full_link = staged_path(ctx.base_dir, &link.text)?;
+
+    println!("Linking:");
+    println!("  Target: {}", target.text);
+    println!("  Link: {:?}", full_link);
+
+    if let Some(parent) = full_link.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    // Replace a stale link left by an earlier directive instead of failing
+    if full_link.symlink_metadata().is_ok() {
+        fs::remove_file(&full_link)?;
+    }
+    std::os::unix::fs::symlink(&target.text, &full_link)?;
+    ctx.record(&full_link, FileAttributes::root(0o777));
+
+    Ok(())
+}
+
+fn process_mkdir_line(args: &[Token], mut ctx: Staging) -> Result<(), Box<dyn std::error::Error>> {
+    // Example: mkdir -m755 "/var/lib/twincan"
+    //          mkdir -o twincan -g twincan -m 750 "/var/lib/twincan"
+    let (options, dirs) = parse_options(args, "p")?;
+    let attrs = options.attributes("755", ctx.owners)?;
+
+    if dirs.is_empty() {
+        return Err("Expected: mkdir [-m<mode>] [-o<owner>] [-g<group>] \"<dir>\"...".into());
+    }
 
-    // Set permissions
-    let mut perms = fs::metadata(&full_dest)?.permissions();
-    perms.set_mode(mode);
-    fs::set_permissions(&full_dest, perms)?;
+    for dir in dirs {
+        let full_dir = staged_path(ctx.base_dir, &dir.text)?;
+        if full_dir.is_symlink() {
+            return Err(format!("Directory is a symlink: {}", dir.text).into());
+        }
+
+        println!("Creating directory:");
+        println!("  Mode: {:o}", attrs.mode);
+        println!("  Owner: {}:{}", attrs.user, attrs.group);
+        println!("  Dir: {:?}", full_dir);
+
+        fs::create_dir_all(&full_dir)?;
+        fs::set_permissions(&full_dir, fs::Permissions::from_mode(attrs.mode))?;
+        ctx.record(&full_dir, attrs.clone());
+    }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tokenize_quoting() {
+        for (line, expected) in [
+            (
+                "install -m755 a to /usr/bin",
+                &[
+                    ("install", false),
+                    ("-m755", false),
+                    ("a", false),
+                    ("to", false),
+                    ("/usr/bin", false),
+                ][..],
+            ),
+            (
+                r#"install "my file" 'to' as"#,
+                &[
+                    ("install", false),
+                    ("my file", true),
+                    ("to", true),
+                    ("as", false),
+                ],
+            ),
+            (r#""a\"b\\c\d""#, &[(r#"a"b\c\d"#, true)]),
+            (r"'a\b' c\ d", &[(r"a\b", true), ("c d", true)]),
+            (r#"pre"mid"post"#, &[("premidpost", true)]),
+            ("  spaced\tout  ", &[("spaced", false), ("out", false)]),
+            ("''", &[("", true)]),
+            ("", &[]),
+        ] {
+            let tokens: Vec<(String, bool)> = tokenize(line)
+                .unwrap()
+                .into_iter()
+                .map(|t| (t.text, t.quoted))
+                .collect();
+            let expected: Vec<(String, bool)> = expected
+                .iter()
+                .map(|(text, quoted)| (text.to_string(), *quoted))
+                .collect();
+            assert_eq!(tokens, expected, "{}", line);
+        }
+    }
+
+    #[test]
+    fn tokenize_errors() {
+        for (line, message) in [
+            ("'open", "Unterminated single quote"),
+            (r#""open"#, "Unterminated double quote"),
+            (r#""open\"#, "Unterminated double quote"),
+            (r"trailing\", "Trailing backslash"),
+        ] {
+            let error = tokenize(line).err().unwrap().to_string();
+            assert_eq!(error, message, "{}", line);
+        }
+    }
+
+    #[test]
+    fn source_paths_stay_in_source_dir() {
+        let source_dir = Path::new("pkg");
+        for (source, expected) in [
+            ("bin/app", "pkg/bin/app"),
+            ("./bin/../app", "pkg/app"),
+            ("a/./b/", "pkg/a/b"),
+        ] {
+            let path = source_path(source_dir, source).unwrap();
+            assert_eq!(path, Path::new(expected), "{}", source);
+        }
+        for source in ["/etc/shadow", "../secret", "bin/../../secret"] {
+            assert!(source_path(source_dir, source).is_err(), "{}", source);
+        }
+    }
+
+    /// A fresh `src` directory holding `app` and `README`, and an empty `pkg` staging directory.
+    fn fixture(name: &str) -> PathBuf {
+        let root = std::env::temp_dir().join(format!("chmod-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(root.join("src")).unwrap();
+        fs::create_dir_all(root.join("pkg")).unwrap();
+        fs::write(root.join("src/app"), "app").unwrap();
+        fs::write(root.join("src/README"), "readme").unwrap();
+        root
+    }
+
+    fn run(root: &Path, line: &str, attributes: &mut AttributeTable) -> Result<(), String> {
+        let source_dir = root.join("src");
+        process_line(
+            line,
+            7,
+            &root.join("pkg"),
+            source_dir.to_str().unwrap(),
+            false,
+            &Owners::default(),
+            attributes,
+        )
+        .map_err(|e| e.to_string())
+    }
+
+    #[test]
+    fn directives() {
+        let root = fixture("directives");
+        let pkg = root.join("pkg");
+        let mut attributes = AttributeTable::default();
+        for line in [
+            r#"symlink "/opt/app/bin/app" to "/usr/bin/app""#,
+            r#"mkdir -m 750 "/var/lib/app" "/var/cache/app""#,
+            r#"install -m755 "app" as "/usr/lib/app/renamed""#,
+        ] {
+            run(&root, line, &mut attributes).unwrap();
+        }
+
+        let link = pkg.join("usr/bin/app");
+        assert_eq!(fs::read_link(&link).unwrap(), Path::new("/opt/app/bin/app"));
+        let attrs = attributes.get(&pkg, Path::new("usr/bin/app")).unwrap();
+        assert_eq!(attrs, FileAttributes::root(0o777));
+
+        for dir in ["var/lib/app", "var/cache/app"] {
+            assert!(pkg.join(dir).is_dir(), "{}", dir);
+            let attrs = attributes.get(&pkg, Path::new(dir)).unwrap();
+            assert_eq!(attrs, FileAttributes::root(0o750), "{}", dir);
+        }
+
+        let renamed = pkg.join("usr/lib/app/renamed");
+        assert_eq!(fs::read_to_string(&renamed).unwrap(), "app");
+        let mode = fs::metadata(&renamed).unwrap().permissions().mode() & 0o7777;
+        assert_eq!(mode, 0o755);
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn directive_errors() {
+        let root = fixture("directive-errors");
+        for (line, message) in [
+            (
+                "copy app /usr/bin",
+                "line 7: Unknown directive 'copy': copy app /usr/bin",
+            ),
+            ("install app", "line 7: Missing destination"),
+            ("symlink /a /b", "line 7: Expected: symlink"),
+            ("symlink /a 'to' /b", "line 7: Expected: symlink"),
+            ("mkdir -m 750", "line 7: Expected: mkdir"),
+            ("mkdir -m 9999 /a", "line 7: Invalid mode '9999'"),
+            (
+                "install app as /a /b",
+                "line 7: Expected exactly one destination after 'as'",
+            ),
+            (
+                "install app README as /a",
+                "line 7: 'as' takes exactly one source file",
+            ),
+        ] {
+            let error = run(&root, line, &mut AttributeTable::default()).unwrap_err();
+            assert!(error.starts_with(message), "{}: {}", line, error);
+        }
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn install_destinations() {
+        let root = fixture("install-destinations");
+        let pkg = root.join("pkg");
+        fs::write(root.join("src/to"), "to").unwrap();
+        fs::create_dir_all(pkg.join("opt/app")).unwrap();
+        for (line, expected) in [
+            // `to` always names a directory, with or without a trailing slash
+            ("install app to /usr/bin", &["usr/bin/app"][..]),
+            ("install app to /usr/lib/app/", &["usr/lib/app/app"]),
+            ("install app to /usr/bin/tool", &["usr/bin/tool/app"]),
+            (
+                "install app README to /usr/share/doc/app",
+                &["usr/share/doc/app/app", "usr/share/doc/app/README"],
+            ),
+            // `as` is the exact path of the file
+            ("install app as /usr/bin/renamed", &["usr/bin/renamed"]),
+            ("install 'to' as /etc/to", &["etc/to"]),
+            // coreutils: a file unless DEST is an existing directory
+            ("install app /opt/app", &["opt/app/app"]),
+            ("install app /opt/app/tool", &["opt/app/tool"]),
+            ("install -D app /etc/app/app.conf", &["etc/app/app.conf"]),
+            (
+                "install app README /opt/app",
+                &["opt/app/app", "opt/app/README"],
+            ),
+            (
+                "install -D app README /var/lib/app",
+                &["var/lib/app/app", "var/lib/app/README"],
+            ),
+            // -t names the directory up front
+            ("install -t /opt/app README", &["opt/app/README"]),
+            (
+                "install -Dm644 -t /usr/share/app app README",
+                &["usr/share/app/app", "usr/share/app/README"],
+            ),
+            ("install --target-directory=/opt/app app", &["opt/app/app"]),
+        ] {
+            run(&root, line, &mut AttributeTable::default()).unwrap();
+            for path in expected {
+                assert!(pkg.join(path).is_file(), "{}: {}", line, path);
+            }
+        }
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn install_errors() {
+        let root = fixture("install-errors");
+        let pkg = root.join("pkg");
+        let outside = root.join("outside");
+        fs::create_dir_all(&outside).unwrap();
+        fs::write(root.join("secret"), "secret").unwrap();
+        fs::create_dir_all(pkg.join("usr")).unwrap();
+        std::os::unix::fs::symlink(&outside, pkg.join("usr/lib")).unwrap();
+        std::os::unix::fs::symlink(&outside, pkg.join("linked")).unwrap();
+
+        for (line, message) in [
+            (
+                "install app /missing/app",
+                "Parent of /missing/app does not exist",
+            ),
+            (
+                "install app README /missing",
+                "Target directory does not exist",
+            ),
+            ("install -t /missing app", "Target directory does not exist"),
+            ("install -t /opt app to /opt", "'-t' cannot be combined"),
+            ("install -t /opt", "Missing source file"),
+            ("install to /usr/bin", "Missing source file"),
+            ("install -x app /a", "Unknown option '-x'"),
+            (
+                "install app as /../../etc/passwd",
+                "escapes the staging root",
+            ),
+            ("install app to /usr/../..", "escapes the staging root"),
+            ("install -D app /../app", "escapes the staging root"),
+            ("install app as /usr/lib/app", "passes through a symlink"),
+            ("install app to /usr/lib/app", "passes through a symlink"),
+            ("install app to /linked", "Target directory is a symlink"),
+            ("install -t /linked app", "Target directory is a symlink"),
+            ("install ../secret as /etc/secret", "Source escapes"),
+            (
+                "install /etc/passwd as /etc/passwd",
+                "Source must be relative",
+            ),
+        ] {
+            let error = run(&root, line, &mut AttributeTable::default()).unwrap_err();
+            assert!(error.contains(message), "{}: {}", line, error);
+        }
+        assert_eq!(fs::read_dir(&outside).unwrap().count(), 0);
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn install_replaces_staged_symlink() {
+        let root = fixture("install-symlink");
+        let pkg = root.join("pkg");
+        let outside = root.join("outside");
+        fs::write(&outside, "outside").unwrap();
+        fs::create_dir_all(pkg.join("usr/bin")).unwrap();
+        std::os::unix::fs::symlink(&outside, pkg.join("usr/bin/app")).unwrap();
+
+        run(
+            &root,
+            "install app as /usr/bin/app",
+            &mut AttributeTable::default(),
+        )
+        .unwrap();
+        assert!(!pkg.join("usr/bin/app").is_symlink());
+        assert_eq!(fs::read_to_string(&outside).unwrap(), "outside");
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn owners_default_to_primary_group() {
+        let root = fixture("owners");
+        let pkg = root.join("pkg");
+        let metadata = Metadata {
+            users: vec![(1, "user twincan -u 950".to_string())],
+            ..Default::default()
+        };
+        let owners = Owners::from_metadata(&metadata).unwrap();
+        let mut attributes = AttributeTable::default();
+        let mut stage = |line: &str| {
+            process_line(line, 3, &pkg, "src", false, &owners, &mut attributes)
+                .map_err(|e| e.to_string())
+        };
+
+        stage("mkdir -o twincan -m 750 /var/lib/twincan").unwrap();
+        stage("mkdir -o twincan -g games /var/lib/twincan/scores").unwrap();
+        let error = stage("mkdir -o twincann /var/lib/other").unwrap_err();
+        assert_eq!(
+            error,
+            "line 3: Unknown user 'twincann': declare it in the users block: \
+             mkdir -o twincann /var/lib/other"
+        );
+        let error = stage("mkdir -g twincans /var/lib/other").unwrap_err();
+        assert!(error.contains("Unknown group 'twincans'"), "{}", error);
+
+        for (path, group, gid) in [
+            ("var/lib/twincan", "twincan", 950),
+            ("var/lib/twincan/scores", "games", 0),
+        ] {
+            let attrs = attributes.get(&pkg, Path::new(path)).unwrap();
+            assert_eq!(
+                (attrs.user.as_str(), attrs.uid),
+                ("twincan", 950),
+                "{}",
+                path
+            );
+            assert_eq!((attrs.group.as_str(), attrs.gid), (group, gid), "{}", path);
+        }
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
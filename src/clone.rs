@@ -9,7 +9,7 @@ pub async fn fetch_source(source: &str) -> Result<String, Box<dyn std::error::Er
         // Try to get filename from URL
         let filename = source
             .split('/')
-            .last()
+            .next_back()
             .unwrap_or("downloaded_file")
             .split('?')
             .next()
@@ -49,7 +49,7 @@ pub async fn fetch_source(source: &str) -> Result<String, Box<dyn std::error::Er
             .to_string();
 
         // If it's already in the current directory, no need to copy
-        if path.parent().map_or(true, |p| p.as_os_str().is_empty()) {
+        if path.parent().is_none_or(|p| p.as_os_str().is_empty()) {
             return Ok(filename);
         }
 
@@ -0,0 +1,200 @@
+use std::io::{self, Write};
+use std::str::FromStr;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Algorithm {
+    None,
+    Gzip,
+    Bzip2,
+    Xz,
+    Zstd,
+    Lz4,
+}
+
+impl Algorithm {
+    const ALL: [(&'static str, Algorithm); 6] = [
+        ("none", Algorithm::None),
+        ("gzip", Algorithm::Gzip),
+        ("bzip2", Algorithm::Bzip2),
+        ("xz", Algorithm::Xz),
+        ("zstd", Algorithm::Zstd),
+        ("lz4", Algorithm::Lz4),
+    ];
+
+    fn name(self) -> &'static str {
+        Self::ALL
+            .iter()
+            .find(|(_, algorithm)| *algorithm == self)
+            .map(|(name, _)| *name)
+            .unwrap_or_default()
+    }
+
+    /// Range of accepted levels, or `None` if the algorithm takes no level.
+    fn levels(self) -> Option<(u32, u32)> {
+        match self {
+            Algorithm::Gzip | Algorithm::Xz => Some((0, 9)),
+            Algorithm::Bzip2 => Some((1, 9)),
+            Algorithm::Zstd => Some((1, 22)),
+            Algorithm::None | Algorithm::Lz4 => None,
+        }
+    }
+}
+
+/// A compression algorithm with an optional level, written `ALGO[:LEVEL]`, e.g. `zstd:19`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Compression {
+    pub algorithm: Algorithm,
+    pub level: Option<u32>,
+}
+
+impl Compression {
+    pub const fn new(algorithm: Algorithm, level: Option<u32>) -> Self {
+        Self { algorithm, level }
+    }
+
+    /// File extension for a stream compressed with this algorithm, including the dot.
+    pub fn extension(&self) -> &'static str {
+        match self.algorithm {
+            Algorithm::None => "",
+            Algorithm::Gzip => ".gz",
+            Algorithm::Bzip2 => ".bz2",
+            Algorithm::Xz => ".xz",
+            Algorithm::Zstd => ".zst",
+            Algorithm::Lz4 => ".lz4",
+        }
+    }
+
+    /// Wrap `inner` in an encoder that compresses everything written to it. Only the stream
+    /// formats used for tarballs are supported; call [`Encoder::finish`] to end the stream.
+    pub fn writer<W: Write>(&self, inner: W) -> Result<Encoder<W>, Box<dyn std::error::Error>> {
+        Ok(match self.algorithm {
+            Algorithm::None => Encoder::None(inner),
+            Algorithm::Gzip => {
+                let level = flate2::Compression::new(self.level.unwrap_or(6));
+                Encoder::Gzip(flate2::write::GzEncoder::new(inner, level))
+            }
+            Algorithm::Bzip2 => {
+                let level = bzip2::Compression::new(self.level.unwrap_or(9));
+                Encoder::Bzip2(bzip2::write::BzEncoder::new(inner, level))
+            }
+            Algorithm::Xz => Encoder::Xz(liblzma::write::XzEncoder::new(
+                inner,
+                self.level.unwrap_or(6),
+            )),
+            Algorithm::Zstd => Encoder::Zstd(zstd::stream::write::Encoder::new(
+                inner,
+                self.level.unwrap_or(3) as i32,
+            )?),
+            Algorithm::Lz4 => return Err("lz4 is not supported for tarballs".into()),
+        })
+    }
+}
+
+/// A compressing writer returned by [`Compression::writer`].
+pub enum Encoder<W: Write> {
+    None(W),
+    Gzip(flate2::write::GzEncoder<W>),
+    Bzip2(bzip2::write::BzEncoder<W>),
+    Xz(liblzma::write::XzEncoder<W>),
+    Zstd(zstd::stream::write::Encoder<'static, W>),
+}
+
+impl<W: Write> Encoder<W> {
+    /// Write the end of the compressed stream and return the inner writer.
+    pub fn finish(self) -> io::Result<W> {
+        match self {
+            Encoder::None(inner) => Ok(inner),
+            Encoder::Gzip(encoder) => encoder.finish(),
+            Encoder::Bzip2(encoder) => encoder.finish(),
+            Encoder::Xz(encoder) => encoder.finish(),
+            Encoder::Zstd(encoder) => encoder.finish(),
+        }
+    }
+}
+
+impl<W: Write> Write for Encoder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Encoder::None(inner) => inner.write(buf),
+            Encoder::Gzip(encoder) => encoder.write(buf),
+            Encoder::Bzip2(encoder) => encoder.write(buf),
+            Encoder::Xz(encoder) => encoder.write(buf),
+            Encoder::Zstd(encoder) => encoder.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Encoder::None(inner) => inner.flush(),
+            Encoder::Gzip(encoder) => encoder.flush(),
+            Encoder::Bzip2(encoder) => encoder.flush(),
+            Encoder::Xz(encoder) => encoder.flush(),
+            Encoder::Zstd(encoder) => encoder.flush(),
+        }
+    }
+}
+
+impl FromStr for Compression {
+    type Err = Box<dyn std::error::Error>;
+
+    fn from_str(spec: &str) -> Result<Self, Self::Err> {
+        let (name, level) = match spec.split_once(':') {
+            Some((name, level)) => (name.trim(), Some(level.trim())),
+            None => (spec.trim(), None),
+        };
+        let algorithm = Algorithm::ALL
+            .iter()
+            .find(|(known, _)| *known == name)
+            .map(|(_, algorithm)| *algorithm)
+            .ok_or_else(|| format!("Unknown compression algorithm '{}'", name))?;
+
+        let level = match (level, algorithm.levels()) {
+            (None, _) => None,
+            (Some(level), Some((min, max))) => {
+                let level = level
+                    .parse::<u32>()
+                    .ok()
+                    .filter(|level| (min..=max).contains(level))
+                    .ok_or_else(|| {
+                        format!(
+                            "{} level must be between {} and {}: {}",
+                            name, min, max, level
+                        )
+                    })?;
+                Some(level)
+            }
+            (Some(_), None) => return Err(format!("{} does not take a level", name).into()),
+        };
+
+        Ok(Compression { algorithm, level })
+    }
+}
+
+/// Pick the compression for a format: the command line wins over the metadata value, which
+/// wins over `default`. The result must use one of the `supported` algorithms.
+pub fn resolve(
+    format: &str,
+    cli: Option<&str>,
+    metadata_value: &str,
+    default: Compression,
+    supported: &[Algorithm],
+) -> Result<Compression, Box<dyn std::error::Error>> {
+    let compression = match cli.filter(|spec| !spec.is_empty()) {
+        Some(spec) => spec.parse()?,
+        None if !metadata_value.is_empty() => metadata_value.parse()?,
+        None => default,
+    };
+
+    if !supported.contains(&compression.algorithm) {
+        let names: Vec<&str> = supported.iter().map(|a| a.name()).collect();
+        return Err(format!(
+            "{} packages cannot use {} compression (supported: {})",
+            format,
+            compression.algorithm.name(),
+            names.join(", ")
+        )
+        .into());
+    }
+
+    Ok(compression)
+}
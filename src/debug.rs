@@ -0,0 +1,112 @@
+use crate::attributes::{AttributeTable, FileAttributes};
+use crate::elf;
+use crate::metadata::Metadata;
+use crate::version;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Where detached debug files are installed, named after the build ID like gdb looks them up.
+pub const BUILD_ID_DIR: &str = "usr/lib/debug/.build-id";
+
+/// A companion package holding the debug symbols split off a staged package.
+pub struct DebugPackage {
+    pub metadata: Metadata,
+    pub pkg_dir: String,
+    pub attributes: AttributeTable,
+}
+
+/// With the `debug` option, move the DWARF sections of the ELF files staged in `pkg_dir` into a
+/// companion package named `<name><suffix>` staged in `<name><suffix><extension>`.
+/// The stripped files get a `.gnu_debuglink` to their debug file.
+pub fn split_debug_info(
+    metadata: &Metadata,
+    pkg_dir: &str,
+    suffix: &str,
+    extension: &str,
+) -> Result<Option<DebugPackage>, Box<dyn std::error::Error>> {
+    if !metadata.has_option("debug") {
+        return Ok(None);
+    }
+
+    let base_dir = Path::new(pkg_dir);
+    let name = format!("{}{}", metadata.name, suffix);
+    let debug_pkg_dir = format!("{}{}", name, extension);
+    let debug_dir = PathBuf::from(&debug_pkg_dir);
+    if debug_dir.exists() {
+        fs::remove_dir_all(&debug_dir)?;
+    }
+
+    let mut attributes = AttributeTable::default();
+    for rel_path in elf::staged_elf_files(base_dir)? {
+        let path = base_dir.join(&rel_path);
+        let data = fs::read(&path)?;
+        if !elf::has_debug_info(&data)? {
+            continue;
+        }
+        let Some(build_id) = elf::build_id(&data)?.filter(|id| id.len() > 1) else {
+            println!(
+                "Skipping debug info of /{}: no build ID",
+                rel_path.display()
+            );
+            continue;
+        };
+
+        // .build-id/ab/cdef....debug
+        let build_id = hex::encode(build_id);
+        let file_name = format!("{}.debug", &build_id[2..]);
+        let debug_path = Path::new(BUILD_ID_DIR)
+            .join(&build_id[..2])
+            .join(&file_name);
+
+        let debug_data =
+            elf::only_keep_debug(&data).map_err(|e| format!("/{}: {}", rel_path.display(), e))?;
+        let stripped = elf::strip_debug(&data, &file_name, &debug_data)
+            .map_err(|e| format!("/{}: {}", rel_path.display(), e))?;
+        fs::write(&path, stripped)?;
+
+        fs::create_dir_all(debug_dir.join(&debug_path).parent().unwrap_or(&debug_dir))?;
+        fs::write(debug_dir.join(&debug_path), debug_data)?;
+        attributes.insert(&debug_path, FileAttributes::root(0o644));
+        println!(
+            "Debug info: /{} -> /{}",
+            rel_path.display(),
+            debug_path.display()
+        );
+    }
+
+    if !debug_dir.exists() {
+        println!("No debug info found for {}", metadata.name);
+        return Ok(None);
+    }
+
+    // Depends on the exact build of the package it describes; no scripts, config or units
+    let full_version = version::full_version(metadata);
+    let metadata = Metadata {
+        name,
+        description: format!("Detached debugging symbols for {}", metadata.name),
+        alpm_depends: Vec::new(),
+        deb_depends: vec![format!("{} (= {})", metadata.name, full_version)],
+        rpm_depends: vec![format!("{} = {}", metadata.name, full_version)],
+        provides: Vec::new(),
+        conflicts: Vec::new(),
+        backup: Vec::new(),
+        systemd_units: Vec::new(),
+        users: Vec::new(),
+        pre_install: String::new(),
+        post_install: String::new(),
+        pre_upgrade: String::new(),
+        post_upgrade: String::new(),
+        pre_remove: String::new(),
+        post_remove: String::new(),
+        package_instructions: Vec::new(),
+        packages: Vec::new(),
+        is_debug: true,
+        ..metadata.clone()
+    };
+
+    Ok(Some(DebugPackage {
+        metadata,
+        pkg_dir: debug_pkg_dir,
+        attributes,
+    }))
+}
@@ -0,0 +1,516 @@
+use crate::metadata::Metadata;
+use std::path::Path;
+
+/// Main categories of the freedesktop.org menu specification; every entry needs at least one.
+const MAIN_CATEGORIES: &[&str] = &[
+    "AudioVideo",
+    "Audio",
+    "Video",
+    "Development",
+    "Education",
+    "Game",
+    "Graphics",
+    "Network",
+    "Office",
+    "Science",
+    "Settings",
+    "System",
+    "Utility",
+];
+
+/// Keys accepted in the `desktop:` block.
+const DESKTOP_KEYS: &[&str] = &[
+    "Name",
+    "GenericName",
+    "Comment",
+    "Icon",
+    "Categories",
+    "Keywords",
+    "MimeType",
+    "Terminal",
+    "StartupWMClass",
+];
+
+const BOOLEAN_KEYS: &[&str] = &[
+    "Terminal",
+    "NoDisplay",
+    "Hidden",
+    "StartupNotify",
+    "DBusActivatable",
+    "PrefersNonDefaultGPU",
+    "SingleMainWindow",
+];
+
+const LIST_KEYS: &[&str] = &[
+    "Categories",
+    "Keywords",
+    "MimeType",
+    "OnlyShowIn",
+    "NotShowIn",
+    "Actions",
+    "Implements",
+];
+
+/// File name of the generated desktop entry, which is also its desktop file ID.
+pub fn file_name(metadata: &Metadata) -> String {
+    format!("{}.desktop", metadata.name)
+}
+
+/// Generate the desktop entry described by the `desktop:` block, or `None` without one.
+/// `Exec` runs `appimage_exec`, by its installed path in native packages and by its file name
+/// in an AppImage, whose runtime puts it on the `PATH`. `Icon` defaults to the package name.
+///
+///   desktop: {
+///       Name = TwinCAN
+///       Categories = Utility;FileManager
+///       MimeType = ["inode/directory"]
+///       Terminal = false
+///   }
+pub fn generate(
+    metadata: &Metadata,
+    is_appimage: bool,
+) -> Result<Option<String>, Box<dyn std::error::Error>> {
+    if metadata.desktop.is_empty() {
+        return Ok(None);
+    }
+    let exec_path = Path::new(&metadata.appimage_exec);
+    let exec = if is_appimage {
+        exec_path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .ok_or("The desktop block requires appimage_exec")?
+    } else if exec_path.is_absolute() {
+        exec_argument(&metadata.appimage_exec)
+    } else {
+        return Err(format!(
+            "The desktop block requires appimage_exec to be an absolute path, got '{}'",
+            metadata.appimage_exec
+        )
+        .into());
+    };
+
+    let mut fields: Vec<(String, String)> = Vec::new();
+    for (line_no, line) in &metadata.desktop {
+        let (key, value) = line
+            .split_once('=')
+            .map(|(key, value)| (key.trim(), value.trim()))
+            .ok_or_else(|| format!("line {}: expected `Key = value`: {}", line_no, line))?;
+        if !DESKTOP_KEYS.contains(&key) {
+            return Err(format!(
+                "line {}: unsupported desktop key '{}' (supported: {})",
+                line_no,
+                key,
+                DESKTOP_KEYS.join(", ")
+            )
+            .into());
+        }
+        // Lists may be written as `a;b` or `["a", "b"]`; both end with `;` in the entry
+        let value = if LIST_KEYS.contains(&key) {
+            let items: Vec<String> = if value.starts_with('[') {
+                value
+                    .trim_start_matches('[')
+                    .trim_end_matches(']')
+                    .split(',')
+                    .map(|item| item.trim().trim_matches('"').to_string())
+                    .collect()
+            } else {
+                value
+                    .split(';')
+                    .map(|item| item.trim().to_string())
+                    .collect()
+            };
+            let items: Vec<String> = items.into_iter().filter(|i| !i.is_empty()).collect();
+            format!("{};", items.join(";"))
+        } else {
+            value.trim_matches('"').to_string()
+        };
+        if fields.iter().any(|(k, _)| k == key) {
+            return Err(format!("line {}: desktop key '{}' is given twice", line_no, key).into());
+        }
+        fields.push((key.to_string(), value));
+    }
+
+    let field = |key: &str| {
+        fields
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, value)| value.clone())
+    };
+    let mut entry = String::from("[Desktop Entry]\nType=Application\n");
+    entry.push_str(&format!(
+        "Name={}\n",
+        field("Name").unwrap_or(metadata.name.clone())
+    ));
+    if let Some(generic_name) = field("GenericName") {
+        entry.push_str(&format!("GenericName={}\n", generic_name));
+    }
+    entry.push_str(&format!(
+        "Comment={}\n",
+        field("Comment").unwrap_or(metadata.description.clone())
+    ));
+    // Files are passed when the entry handles MIME types
+    let mime_type = field("MimeType");
+    entry.push_str(&format!(
+        "Exec={}{}\n",
+        exec,
+        if mime_type.is_some() { " %F" } else { "" }
+    ));
+    entry.push_str(&format!(
+        "Icon={}\n",
+        field("Icon").unwrap_or(metadata.name.clone())
+    ));
+    entry.push_str(&format!(
+        "Terminal={}\n",
+        field("Terminal").unwrap_or("false".to_string())
+    ));
+    for key in ["Categories", "Keywords", "StartupWMClass"] {
+        if let Some(value) = field(key) {
+            entry.push_str(&format!("{}={}\n", key, value));
+        }
+    }
+    if let Some(mime_type) = mime_type {
+        entry.push_str(&format!("MimeType={}\n", mime_type));
+    }
+
+    validate(&entry).map_err(|e| format!("Generated desktop entry: {}", e))?;
+    Ok(Some(entry))
+}
+
+/// Quote a path for `Exec` as the desktop entry specification requires: reserved characters
+/// need double quotes, inside which `"`, `` ` ``, `$` and `\` are backslash-escaped. The
+/// backslashes are escaped once more for the string value, and `%` is doubled.
+fn exec_argument(path: &str) -> String {
+    let escaped = path.replace('%', "%%");
+    if !escaped
+        .chars()
+        .any(|c| " \t\n\"'\\><~|&;$*?#()`".contains(c))
+    {
+        return escaped;
+    }
+    let mut quoted = String::from("\"");
+    for c in escaped.chars() {
+        match c {
+            '"' | '`' | '$' => quoted.push_str(&format!("\\\\{}", c)),
+            '\\' => quoted.push_str("\\\\\\\\"),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// A `[Group]` of a desktop entry with its `key=value` pairs.
+type Group = (String, Vec<(String, String)>);
+
+/// Parse a desktop entry into its groups of `key=value` pairs.
+pub fn parse(contents: &str) -> Result<Vec<Group>, String> {
+    let mut groups: Vec<Group> = Vec::new();
+    for (index, line) in contents.lines().enumerate() {
+        let line_no = index + 1;
+        let line = line.trim_end();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if let Some(group) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+            if groups.iter().any(|(name, _)| name == group) {
+                return Err(format!(
+                    "line {}: group [{}] is given twice",
+                    line_no, group
+                ));
+            }
+            groups.push((group.to_string(), Vec::new()));
+            continue;
+        }
+        let Some((_, entries)) = groups.last_mut() else {
+            return Err(format!("line {}: entry before the first group", line_no));
+        };
+        let (key, value) = line
+            .split_once('=')
+            .map(|(key, value)| (key.trim(), value.trim()))
+            .ok_or_else(|| format!("line {}: expected `Key=value`: {}", line_no, line))?;
+        // Localized keys are written `Name[de]`
+        let base_key = key.split('[').next().unwrap_or(key);
+        if base_key.is_empty()
+            || !base_key
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-')
+        {
+            return Err(format!("line {}: invalid key '{}'", line_no, key));
+        }
+        if entries.iter().any(|(k, _)| k == key) {
+            return Err(format!("line {}: key '{}' is given twice", line_no, key));
+        }
+        entries.push((key.to_string(), value.to_string()));
+    }
+    Ok(groups)
+}
+
+/// Check a desktop entry against the rules of the desktop entry specification that matter for
+/// launching it: the `[Desktop Entry]` group comes first, `Type`, `Name` and for applications
+/// `Exec` are set, booleans are `true` or `false`, lists end with `;` and at least one main
+/// category is given.
+pub fn validate(contents: &str) -> Result<Vec<(String, String)>, String> {
+    let groups = parse(contents)?;
+    let Some((group, entries)) = groups.into_iter().next() else {
+        return Err("no [Desktop Entry] group".to_string());
+    };
+    if group != "Desktop Entry" {
+        return Err(format!(
+            "the first group must be [Desktop Entry], not [{}]",
+            group
+        ));
+    }
+
+    let get = |key: &str| entries.iter().find(|(k, _)| k == key).map(|(_, v)| v);
+    let entry_type = get("Type").ok_or("missing Type")?;
+    if !["Application", "Link", "Directory"].contains(&entry_type.as_str()) {
+        return Err(format!("invalid Type '{}'", entry_type));
+    }
+    if get("Name").is_none_or(|name| name.is_empty()) {
+        return Err("missing Name".to_string());
+    }
+    if entry_type == "Application" && get("Exec").is_none_or(|exec| exec.is_empty()) {
+        return Err("missing Exec".to_string());
+    }
+    for (key, value) in &entries {
+        if BOOLEAN_KEYS.contains(&key.as_str()) && value != "true" && value != "false" {
+            return Err(format!("{} must be true or false, not '{}'", key, value));
+        }
+        if LIST_KEYS.contains(&key.as_str()) && !value.ends_with(';') {
+            return Err(format!("{} must end with ';': {}", key, value));
+        }
+    }
+    if let Some(mime_types) = get("MimeType") {
+        for mime_type in mime_types.split(';').filter(|m| !m.is_empty()) {
+            if mime_type.split('/').count() != 2 {
+                return Err(format!("invalid MimeType '{}'", mime_type));
+            }
+        }
+    }
+    if entry_type == "Application" {
+        let categories = get("Categories").ok_or("missing Categories")?;
+        if !categories
+            .split(';')
+            .any(|category| MAIN_CATEGORIES.contains(&category))
+        {
+            return Err(format!(
+                "Categories must include one of {}: {}",
+                MAIN_CATEGORIES.join(", "),
+                categories
+            ));
+        }
+    }
+
+    Ok(entries)
+}
+
+/// Validate the desktop entry at the AppDir root and check that it launches `appimage_exec`
+/// and names an icon present at the root.
+pub fn validate_appdir(
+    app_dir: &Path,
+    metadata: &Metadata,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut desktop_files = Vec::new();
+    for entry in std::fs::read_dir(app_dir)? {
+        let path = entry?.path();
+        if path.extension().is_some_and(|ext| ext == "desktop") {
+            desktop_files.push(path);
+        }
+    }
+    let [desktop_file] = desktop_files.as_slice() else {
+        return Err(format!(
+            "The AppDir needs exactly one desktop entry at its root, found {}",
+            desktop_files.len()
+        )
+        .into());
+    };
+    let name = desktop_file
+        .file_name()
+        .unwrap_or_default()
+        .to_string_lossy()
+        .into_owned();
+
+    let entries = validate(&std::fs::read_to_string(desktop_file)?)
+        .map_err(|e| format!("{}: {}", name, e))?;
+    let get = |key: &str| entries.iter().find(|(k, _)| k == key).map(|(_, v)| v);
+
+    let exec = get("Exec")
+        .and_then(|exec| exec.split_whitespace().next())
+        .unwrap_or_default();
+    let expected = Path::new(&metadata.appimage_exec).file_name();
+    if Path::new(exec).file_name() != expected {
+        return Err(format!(
+            "{}: Exec={} does not run appimage_exec {}",
+            name, exec, metadata.appimage_exec
+        )
+        .into());
+    }
+
+    let icon = get("Icon").ok_or_else(|| format!("{}: missing Icon", name))?;
+    let has_icon = ["png", "svg", "xpm"]
+        .iter()
+        .any(|ext| app_dir.join(format!("{}.{}", icon, ext)).is_file());
+    if !has_icon {
+        return Err(format!(
+            "{}: Icon={} has no {}.png, {}.svg or {}.xpm at the AppDir root",
+            name, icon, icon, icon, icon
+        )
+        .into());
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const VALID: &str = "[Desktop Entry]\n\
+                         Type=Application\n\
+                         Name=TwinCAN\n\
+                         Name[de]=TwinCAN\n\
+                         Exec=twincan %F\n\
+                         Terminal=false\n\
+                         Categories=Utility;FileManager;\n\
+                         MimeType=inode/directory;\n\
+                         \n\
+                         [Desktop Action New]\n\
+                         Name=New Window\n";
+
+    #[test]
+    fn validate_accepts_valid_entry() {
+        let entries = validate(VALID).unwrap();
+        assert_eq!(entries.len(), 7);
+        assert!(entries.contains(&("Name[de]".to_string(), "TwinCAN".to_string())));
+    }
+
+    #[test]
+    fn validate_rejects_invalid_entries() {
+        for (from, to, message) in [
+            ("[Desktop Entry]\n", "", "entry before the first group"),
+            (
+                "[Desktop Entry]\n",
+                "[Desktop Action X]\n",
+                "the first group must be",
+            ),
+            ("Type=Application\n", "", "missing Type"),
+            (
+                "Type=Application\n",
+                "Type=Program\n",
+                "invalid Type 'Program'",
+            ),
+            ("Name=TwinCAN\n", "", "missing Name"),
+            ("Exec=twincan %F\n", "", "missing Exec"),
+            (
+                "Terminal=false\n",
+                "Terminal=no\n",
+                "Terminal must be true or false",
+            ),
+            (
+                "Utility;FileManager;",
+                "Utility;FileManager",
+                "Categories must end with ';'",
+            ),
+            (
+                "Utility;FileManager;",
+                "FileManager;",
+                "Categories must include one of",
+            ),
+            (
+                "Categories=Utility;FileManager;\n",
+                "",
+                "missing Categories",
+            ),
+            (
+                "inode/directory;",
+                "directory;",
+                "invalid MimeType 'directory'",
+            ),
+            (
+                "Terminal=false\n",
+                "Terminal=false\nTerminal=true\n",
+                "key 'Terminal' is given twice",
+            ),
+            ("Terminal=false\n", "Terminal\n", "expected `Key=value`"),
+            (
+                "Terminal=false\n",
+                "Term inal=false\n",
+                "invalid key 'Term inal'",
+            ),
+            (
+                "[Desktop Action New]",
+                "[Desktop Entry]",
+                "group [Desktop Entry] is given twice",
+            ),
+        ] {
+            let contents = VALID.replacen(from, to, 1);
+            let error = validate(&contents).unwrap_err();
+            assert!(error.contains(message), "{}: {}", message, error);
+        }
+        assert_eq!(validate("").unwrap_err(), "no [Desktop Entry] group");
+    }
+
+    #[test]
+    fn generate_writes_valid_entry() {
+        let metadata = Metadata {
+            name: "twincan".to_string(),
+            description: "Two-pane file manager".to_string(),
+            appimage_exec: "/usr/bin/twincan".to_string(),
+            desktop: vec![
+                (1, "Name = TwinCAN".to_string()),
+                (2, "Categories = Utility;FileManager".to_string()),
+                (3, "MimeType = [\"inode/directory\"]".to_string()),
+            ],
+            ..Default::default()
+        };
+        let entry = generate(&metadata, false).unwrap().unwrap();
+        assert_eq!(
+            entry,
+            "[Desktop Entry]\n\
+             Type=Application\n\
+             Name=TwinCAN\n\
+             Comment=Two-pane file manager\n\
+             Exec=/usr/bin/twincan %F\n\
+             Icon=twincan\n\
+             Terminal=false\n\
+             Categories=Utility;FileManager;\n\
+             MimeType=inode/directory;\n"
+        );
+
+        // AppRun puts the AppDir's binaries on the PATH
+        let entry = generate(&metadata, true).unwrap().unwrap();
+        assert!(entry.contains("\nExec=twincan %F\n"), "{}", entry);
+
+        for (exec, expected) in [
+            ("/opt/twincan/bin/twincan", "/opt/twincan/bin/twincan"),
+            ("/opt/Twin CAN/twincan", r#""/opt/Twin CAN/twincan""#),
+            (r#"/opt/a"b$c\d%e"#, r#""/opt/a\\"b\\$c\\\\d%%e""#),
+        ] {
+            let metadata = Metadata {
+                appimage_exec: exec.to_string(),
+                ..metadata.clone()
+            };
+            let entry = generate(&metadata, false).unwrap().unwrap();
+            let line = format!("\nExec={} %F\n", expected);
+            assert!(entry.contains(&line), "{}: {}", exec, entry);
+        }
+
+        let relative = Metadata {
+            appimage_exec: "bin/twincan".to_string(),
+            ..metadata.clone()
+        };
+        let error = generate(&relative, false).unwrap_err().to_string();
+        assert!(error.contains("absolute path"), "{}", error);
+
+        let metadata = Metadata {
+            desktop: vec![(4, "Exec = other".to_string())],
+            ..metadata
+        };
+        let error = generate(&metadata, false).unwrap_err().to_string();
+        assert!(
+            error.starts_with("line 4: unsupported desktop key 'Exec'"),
+            "{}",
+            error
+        );
+    }
+}
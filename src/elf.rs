@@ -0,0 +1,300 @@
+use object::build::elf::{Builder, Dynamic, SectionData};
+use object::elf;
+use object::read::Object;
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+/// Staged regular files starting with the ELF magic, relative to `base_dir`, in sorted order.
+pub fn staged_elf_files(base_dir: &Path) -> Result<Vec<PathBuf>, Box<dyn std::error::Error>> {
+    let mut files = Vec::new();
+    for entry in walkdir::WalkDir::new(base_dir).sort_by_file_name() {
+        let entry = entry?;
+        if !entry.file_type().is_file() {
+            continue;
+        }
+        let mut magic = [0u8; 4];
+        let is_elf =
+            File::open(entry.path())?.read_exact(&mut magic).is_ok() && magic == elf::ELFMAG;
+        if is_elf {
+            files.push(entry.path().strip_prefix(base_dir)?.to_path_buf());
+        }
+    }
+    Ok(files)
+}
+
+fn is_debug_section(name: &[u8]) -> bool {
+    name.starts_with(b".debug_") || name.starts_with(b".zdebug_")
+}
+
+/// Whether `data` is an executable or shared library carrying DWARF debug info. Relocatable
+/// objects such as kernel modules are left alone.
+pub fn has_debug_info(data: &[u8]) -> Result<bool, Box<dyn std::error::Error>> {
+    let builder = Builder::read(data)?;
+    if !matches!(builder.header.e_type, elf::ET_EXEC | elf::ET_DYN) {
+        return Ok(false);
+    }
+    Ok(builder
+        .sections
+        .iter()
+        .any(|section| is_debug_section(&section.name)))
+}
+
+/// The GNU build ID from `.note.gnu.build-id`, if any.
+pub fn build_id(data: &[u8]) -> Result<Option<Vec<u8>>, Box<dyn std::error::Error>> {
+    let file = object::File::parse(data)?;
+    Ok(file.build_id()?.map(<[u8]>::to_vec))
+}
+
+/// The ELF `e_machine` of `data`.
+pub fn machine(data: &[u8]) -> Result<elf::Machine, Box<dyn std::error::Error>> {
+    Ok(Builder::read(data)?.header.e_machine)
+}
+
+/// Dynamic linking information of an executable or shared library.
+pub struct DynamicInfo {
+    /// `DT_NEEDED` entries: sonames of the libraries loaded with this file
+    pub needed: Vec<String>,
+    /// `DT_SONAME` of a shared library
+    pub soname: Option<String>,
+    /// Library search path from `DT_RUNPATH` or `DT_RPATH`
+    pub runpath: Vec<String>,
+    pub is_64: bool,
+    pub machine: elf::Machine,
+}
+
+/// The dynamic section of `data`, or `None` for static executables and relocatable objects.
+pub fn dynamic_info(data: &[u8]) -> Result<Option<DynamicInfo>, Box<dyn std::error::Error>> {
+    let builder = Builder::read(data)?;
+    let Some(entries) = builder.dynamic_data() else {
+        return Ok(None);
+    };
+
+    let mut info = DynamicInfo {
+        needed: Vec::new(),
+        soname: None,
+        runpath: Vec::new(),
+        is_64: builder.is_64,
+        machine: builder.header.e_machine,
+    };
+    for entry in entries {
+        if let Dynamic::String { tag, val } = entry {
+            let value = String::from_utf8_lossy(val).into_owned();
+            match *tag {
+                elf::DT_NEEDED => info.needed.push(value),
+                elf::DT_SONAME => info.soname = Some(value),
+                elf::DT_RUNPATH | elf::DT_RPATH => {
+                    info.runpath.extend(value.split(':').map(str::to_string))
+                }
+                _ => {}
+            }
+        }
+    }
+    Ok(Some(info))
+}
+
+/// Equivalent of `objcopy --only-keep-debug`: keep the section headers so addresses still
+/// match, but drop the contents of every loaded section except notes (the build ID).
+pub fn only_keep_debug(data: &[u8]) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let mut builder = Builder::read(data)?;
+    for segment in builder.segments.iter_mut() {
+        segment.delete = true;
+    }
+    for symbol in builder.dynamic_symbols.iter_mut() {
+        symbol.delete = true;
+    }
+    for section in builder.sections.iter_mut() {
+        if section.is_alloc() && section.sh_type != elf::SHT_NOTE {
+            section.sh_type = elf::SHT_NOBITS;
+            section.data = SectionData::UninitializedData(section.sh_size);
+        }
+    }
+    builder.delete_unused_versions();
+
+    let mut buffer = Vec::new();
+    builder.write(&mut buffer)?;
+    Ok(buffer)
+}
+
+/// Equivalent of `strip --strip-debug --discard-all`: remove the DWARF sections and local
+/// symbols of an executable or shared library, keeping the global symbols. Returns `None` when
+/// there is nothing to strip or the file is a relocatable object.
+pub fn strip(data: &[u8]) -> Result<Option<Vec<u8>>, Box<dyn std::error::Error>> {
+    let mut builder = Builder::read(data)?;
+    if !matches!(builder.header.e_type, elf::ET_EXEC | elf::ET_DYN) {
+        return Ok(None);
+    }
+
+    let mut changed = false;
+    for section in builder.sections.iter_mut() {
+        if is_debug_section(&section.name) {
+            section.delete = true;
+            changed = true;
+        }
+    }
+    for symbol in builder.symbols.iter_mut() {
+        if symbol.st_bind() == elf::STB_LOCAL {
+            symbol.delete = true;
+            changed = true;
+        }
+    }
+    if !changed {
+        return Ok(None);
+    }
+
+    builder.delete_orphans();
+    let mut buffer = Vec::new();
+    builder.write(&mut buffer)?;
+    Ok(Some(buffer))
+}
+
+/// Equivalent of `objcopy --strip-debug --add-gnu-debuglink=FILE`: remove the DWARF sections
+/// and point debuggers at the detached `debug_file` named `debuglink`.
+pub fn strip_debug(
+    data: &[u8],
+    debuglink: &str,
+    debug_file: &[u8],
+) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let mut builder = Builder::read(data)?;
+    for section in builder.sections.iter_mut() {
+        if is_debug_section(&section.name) || section.name.as_slice() == b".gnu_debuglink" {
+            section.delete = true;
+        }
+    }
+
+    // The name NUL-terminated and padded to 4 bytes, followed by the CRC32 of the debug file
+    let mut contents = debuglink.as_bytes().to_vec();
+    contents.resize((contents.len() / 4 + 1) * 4, 0);
+    let crc = crc32fast::hash(debug_file);
+    contents.extend_from_slice(&match builder.endian {
+        object::Endianness::Little => crc.to_le_bytes(),
+        object::Endianness::Big => crc.to_be_bytes(),
+    });
+    let section = builder.sections.add();
+    section.name = b".gnu_debuglink"[..].into();
+    section.sh_type = elf::SHT_PROGBITS;
+    section.sh_addralign = 4;
+    section.data = SectionData::Data(contents.into());
+
+    builder.delete_orphans();
+    let mut buffer = Vec::new();
+    builder.write(&mut buffer)?;
+    Ok(buffer)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BUILD_ID: [u8; 8] = [0xab, 0xcd, 1, 2, 3, 4, 5, 6];
+
+    /// A minimal x86-64 shared library with code, a build ID note and one DWARF section.
+    fn fixture() -> Vec<u8> {
+        let mut builder = Builder::new(object::Endianness::Little, true);
+        builder.header.e_type = elf::ET_DYN;
+        builder.header.e_machine = elf::EM_X86_64;
+
+        let text = builder.sections.add();
+        text.name = b".text"[..].into();
+        text.sh_type = elf::SHT_PROGBITS;
+        text.sh_flags = elf::SHF_ALLOC | elf::SHF_EXECINSTR;
+        text.sh_addralign = 16;
+        text.data = SectionData::Data(vec![0xc3; 16].into());
+
+        // namesz, descsz, NT_GNU_BUILD_ID, "GNU\0", then the ID
+        let mut note = Vec::new();
+        for word in [4, BUILD_ID.len() as u32, elf::NT_GNU_BUILD_ID.0] {
+            note.extend_from_slice(&word.to_le_bytes());
+        }
+        note.extend_from_slice(b"GNU\0");
+        note.extend_from_slice(&BUILD_ID);
+        let build_id = builder.sections.add();
+        build_id.name = b".note.gnu.build-id"[..].into();
+        build_id.sh_type = elf::SHT_NOTE;
+        build_id.sh_flags = elf::SHF_ALLOC;
+        build_id.sh_addralign = 4;
+        build_id.data = SectionData::Data(note.into());
+
+        let debug_info = builder.sections.add();
+        debug_info.name = b".debug_info"[..].into();
+        debug_info.sh_type = elf::SHT_PROGBITS;
+        debug_info.sh_addralign = 1;
+        debug_info.data = SectionData::Data(b"dwarf"[..].into());
+
+        let shstrtab = builder.sections.add();
+        shstrtab.name = b".shstrtab"[..].into();
+        shstrtab.sh_type = elf::SHT_STRTAB;
+        shstrtab.data = SectionData::SectionString;
+
+        let mut buffer = Vec::new();
+        builder.write(&mut buffer).unwrap();
+        buffer
+    }
+
+    /// Type and contents of the section called `name`, if present.
+    fn section(data: &[u8], name: &str) -> Option<(elf::SectionType, Vec<u8>)> {
+        let builder = Builder::read(data).unwrap();
+        builder
+            .sections
+            .iter()
+            .find(|section| section.name.as_slice() == name.as_bytes())
+            .map(|section| {
+                let contents = match &section.data {
+                    SectionData::Data(bytes) => bytes.to_vec(),
+                    _ => Vec::new(),
+                };
+                (section.sh_type, contents)
+            })
+    }
+
+    #[test]
+    fn fixture_has_debug_info() {
+        let data = fixture();
+        assert!(has_debug_info(&data).unwrap());
+        assert_eq!(build_id(&data).unwrap(), Some(BUILD_ID.to_vec()));
+        assert_eq!(machine(&data).unwrap(), elf::EM_X86_64);
+    }
+
+    #[test]
+    fn only_keep_debug_drops_loaded_contents() {
+        let debug = only_keep_debug(&fixture()).unwrap();
+
+        assert_eq!(
+            section(&debug, ".text"),
+            Some((elf::SHT_NOBITS, Vec::new()))
+        );
+        let debug_info = (elf::SHT_PROGBITS, b"dwarf".to_vec());
+        assert_eq!(section(&debug, ".debug_info"), Some(debug_info));
+        // gdb matches the debug file to the binary by its build ID
+        assert_eq!(build_id(&debug).unwrap(), Some(BUILD_ID.to_vec()));
+    }
+
+    #[test]
+    fn strip_debug_adds_debuglink() {
+        let data = fixture();
+        let debug = only_keep_debug(&data).unwrap();
+        let stripped = strip_debug(&data, "cdef.debug", &debug).unwrap();
+
+        assert!(!has_debug_info(&stripped).unwrap());
+        assert_eq!(section(&stripped, ".debug_info"), None);
+        let text = (elf::SHT_PROGBITS, vec![0xc3; 16]);
+        assert_eq!(section(&stripped, ".text"), Some(text));
+        assert_eq!(build_id(&stripped).unwrap(), Some(BUILD_ID.to_vec()));
+
+        let mut debuglink = b"cdef.debug\0\0".to_vec();
+        debuglink.extend_from_slice(&crc32fast::hash(&debug).to_le_bytes());
+        let (sh_type, contents) = section(&stripped, ".gnu_debuglink").unwrap();
+        assert_eq!(sh_type, elf::SHT_PROGBITS);
+        assert_eq!(contents, debuglink);
+
+        // Stripping again replaces the link instead of adding a second one
+        let again = strip_debug(&stripped, "cdef.debug", &debug).unwrap();
+        let builder = Builder::read(&again[..]).unwrap();
+        let links = builder
+            .sections
+            .iter()
+            .filter(|section| section.name.as_slice() == b".gnu_debuglink")
+            .count();
+        assert_eq!(links, 1);
+    }
+}
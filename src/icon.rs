@@ -0,0 +1,81 @@
+use crate::metadata::Metadata;
+use resvg::{tiny_skia, usvg};
+use std::fs;
+use std::path::Path;
+
+/// Sizes of the hicolor `NxN/apps` directories the icon is rasterized into.
+const PNG_SIZES: &[u32] = &[16, 22, 24, 32, 48, 64, 128, 256, 512];
+
+/// Size of the PNG used as the AppDir's `.DirIcon`.
+const DIR_ICON_SIZE: u32 = 256;
+
+/// The SVG icon together with its rasterized sizes.
+pub struct Icon {
+    pub svg: Vec<u8>,
+    /// (hicolor size, PNG data)
+    pub pngs: Vec<(u32, Vec<u8>)>,
+}
+
+impl Icon {
+    /// Package paths and contents of the icon in the hicolor theme, named after the package so
+    /// that the generated desktop entry's `Icon=` finds it.
+    pub fn theme_files(&self, metadata: &Metadata) -> Vec<(String, &[u8])> {
+        let mut files = vec![(
+            format!(
+                "/usr/share/icons/hicolor/scalable/apps/{}.svg",
+                metadata.name
+            ),
+            self.svg.as_slice(),
+        )];
+        for (size, png) in &self.pngs {
+            files.push((
+                format!(
+                    "/usr/share/icons/hicolor/{}x{}/apps/{}.png",
+                    size, size, metadata.name
+                ),
+                png.as_slice(),
+            ));
+        }
+        files
+    }
+
+    /// The icon at the AppDir root and the `.DirIcon` file managers show for the AppImage.
+    pub fn appdir_files(&self, metadata: &Metadata) -> Vec<(String, &[u8])> {
+        let mut files = vec![(format!("/{}.svg", metadata.name), self.svg.as_slice())];
+        if let Some((_, png)) = self.pngs.iter().find(|(size, _)| *size == DIR_ICON_SIZE) {
+            files.push(("/.DirIcon".to_string(), png.as_slice()));
+        }
+        files
+    }
+}
+
+/// Load the SVG named by `icon` from the source directory and rasterize it at every hicolor
+/// size, or `None` without an icon. Non-square icons are scaled to fit and centered.
+pub fn render(metadata: &Metadata) -> Result<Option<Icon>, Box<dyn std::error::Error>> {
+    if metadata.icon.is_empty() {
+        return Ok(None);
+    }
+    if !metadata.icon.ends_with(".svg") {
+        return Err(format!("icon must be an SVG file: {}", metadata.icon).into());
+    }
+    let path = Path::new(&metadata.pkgbase).join(&metadata.icon);
+    let svg = fs::read(&path).map_err(|e| format!("Failed to read icon {:?}: {}", path, e))?;
+    let tree = usvg::Tree::from_data(&svg, &usvg::Options::default())
+        .map_err(|e| format!("Failed to parse icon {:?}: {}", path, e))?;
+
+    let size = tree.size();
+    let mut pngs = Vec::new();
+    for &px in PNG_SIZES {
+        let scale = px as f32 / size.width().max(size.height());
+        let transform = tiny_skia::Transform::from_scale(scale, scale).post_translate(
+            (px as f32 - size.width() * scale) / 2.0,
+            (px as f32 - size.height() * scale) / 2.0,
+        );
+        let mut pixmap = tiny_skia::Pixmap::new(px, px).ok_or("Invalid icon size")?;
+        resvg::render(&tree, transform, &mut pixmap.as_mut());
+        pngs.push((px, pixmap.encode_png()?));
+    }
+    println!("Rendered icon {:?} at {} sizes", path, PNG_SIZES.len());
+
+    Ok(Some(Icon { svg, pngs }))
+}
@@ -1,22 +1,66 @@
+mod appstream;
+mod arch;
+mod attributes;
+mod autodeps;
 mod bundle;
 mod checksum;
 mod chmod;
 mod clone;
+mod compression;
+mod debug;
+mod desktop;
+mod elf;
+mod icon;
 mod metadata;
+mod reproducible;
+mod scripts;
+mod strip;
+mod systemd;
 mod unpack;
+mod version;
 
 pub use bundle::linux::alpm::alpm_build;
-pub use bundle::linux::appimage::appimage_build;
+pub use bundle::linux::appimage::{appimage_build, verify_appimage};
 pub use bundle::linux::deb::deb_build;
 pub use bundle::linux::rpm::rpm_build;
 pub use checksum::verify_checksum;
 pub use chmod::chmod_package;
 pub use clone::fetch_source;
 pub use metadata::Metadata;
+pub use reproducible::{check_source_date_epoch, diff_outputs};
 pub use unpack::unpack_source;
+pub use version::{VersionFormat, vercmp};
 
-pub async fn build_package() -> Result<(), Box<dyn std::error::Error>> {
+use std::path::Path;
+
+/// Resolve the architectures to build: the `--arch` values if any, otherwise every
+/// architecture listed in the metadata.
+pub fn target_arches(requested: &[String]) -> Result<Vec<String>, Box<dyn std::error::Error>> {
     let metadata = metadata::extract_metadata("metadata")?;
+    if requested.is_empty() {
+        return Ok(metadata.arch);
+    }
+    for arch in requested {
+        if !metadata.arch.contains(arch) {
+            return Err(format!(
+                "Architecture '{}' is not listed in arch {:?}",
+                arch, metadata.arch
+            )
+            .into());
+        }
+    }
+    Ok(requested.to_vec())
+}
+
+/// Fetch, verify and unpack the sources for `arch`.
+pub async fn build_package(arch: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let metadata = metadata::extract_metadata_for_arch("metadata", Some(arch))?;
+
+    // Start from a clean source directory so files unpacked for another architecture
+    // cannot end up in this one
+    if Path::new(&metadata.name).exists() {
+        std::fs::remove_dir_all(&metadata.name)?;
+    }
 
     for source in &metadata.sources {
         let filename = fetch_source(source).await?;
@@ -1,14 +1,142 @@
 use linux_bundler::alpm_build;
-use linux_bundler::appimage_build;
 use linux_bundler::deb_build;
 use linux_bundler::rpm_build;
+use linux_bundler::{VersionFormat, vercmp};
+use linux_bundler::{appimage_build, verify_appimage};
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::process::Command;
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    linux_bundler::build_package().await?;
-    alpm_build()?;
-    appimage_build()?;
-    deb_build()?;
-    rpm_build()?;
+    let mut args = std::env::args().skip(1).peekable();
+
+    // vercmp <deb|rpm|alpm> <a> <b>: print -1, 0 or 1 like pacman's vercmp
+    if args.peek().map(String::as_str) == Some("vercmp") {
+        let args: Vec<String> = args.skip(1).collect();
+        let [format, a, b] = args.as_slice() else {
+            return Err("Usage: vercmp <deb|rpm|alpm> <version1> <version2>".into());
+        };
+        let ordering = vercmp(format.parse::<VersionFormat>()?, a, b);
+        println!("{}", ordering as i8);
+        return Ok(());
+    }
+
+    // verify-appimage --key <fingerprint|key.asc> <file>: check the signature embedded in a
+    // signed AppImage against a trusted key
+    if args.peek().map(String::as_str) == Some("verify-appimage") {
+        let args: Vec<String> = args.skip(1).collect();
+        let (key, path) = match args.as_slice() {
+            [flag, key, path] if flag == "--key" => (key.as_str(), path),
+            [flag, path] if flag.starts_with("--key=") => (&flag["--key=".len()..], path),
+            _ => {
+                return Err(
+                    "Usage: verify-appimage --key <fingerprint|public key file> <file.AppImage>"
+                        .into(),
+                );
+            }
+        };
+        let signer = verify_appimage(Path::new(path), key)?;
+        println!("Good signature from {}", signer);
+        return Ok(());
+    }
+
+    // --arch <arch> (repeatable, or comma separated) limits the build to those architectures
+    // --compression <format>=<algo>[:<level>] overrides the metadata for one package format
+    // --verify-reproducible builds twice with the same SOURCE_DATE_EPOCH and compares the outputs
+    let mut requested_arches = Vec::new();
+    let mut verify_reproducible = false;
+    let mut forwarded = Vec::new();
+    let mut compression: HashMap<String, String> = HashMap::new();
+    while let Some(arg) = args.next() {
+        if arg == "--verify-reproducible" {
+            verify_reproducible = true;
+            continue;
+        }
+        forwarded.push(arg.clone());
+        let (flag, value) = match arg.split_once('=') {
+            Some((flag, value)) if flag.starts_with("--") => (flag.to_string(), value.to_string()),
+            _ if arg == "--arch" || arg == "--compression" => {
+                let value = args.next().ok_or(format!("{} requires a value", arg))?;
+                forwarded.push(value.clone());
+                (arg, value)
+            }
+            _ => return Err(format!("Unknown argument: {}", arg).into()),
+        };
+        match flag.as_str() {
+            "--arch" => requested_arches.extend(value.split(',').map(|a| a.trim().to_string())),
+            "--compression" => {
+                let (format, spec) = value
+                    .split_once('=')
+                    .filter(|(format, _)| ["alpm", "appimage", "deb", "rpm"].contains(format))
+                    .ok_or("--compression expects <alpm|appimage|deb|rpm>=<algo>[:<level>]")?;
+                compression.insert(format.to_string(), spec.to_string());
+            }
+            _ => return Err(format!("Unknown argument: {}", flag).into()),
+        }
+    }
+    linux_bundler::check_source_date_epoch()?;
+    if verify_reproducible {
+        return verify(&forwarded);
+    }
+
+    let compression_for = |format: &str| compression.get(format).map(String::as_str);
+
+    for arch in linux_bundler::target_arches(&requested_arches)? {
+        println!("==> Building for {}", arch);
+        linux_bundler::build_package(&arch).await?;
+        alpm_build(&arch, compression_for("alpm"))?;
+        appimage_build(&arch, compression_for("appimage"))?;
+        deb_build(&arch, compression_for("deb"))?;
+        rpm_build(&arch, compression_for("rpm"))?;
+    }
     Ok(())
 }
+
+/// Run the build twice in fresh processes with a fixed SOURCE_DATE_EPOCH and report any output
+/// file that differs. The first build is kept in output.first for inspection.
+fn verify(args: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+    let epoch = std::env::var("SOURCE_DATE_EPOCH").unwrap_or_else(|_| {
+        std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default()
+            .to_string()
+    });
+    let output = Path::new("output");
+    let first = Path::new("output.first");
+
+    for (round, label) in ["first", "second"].iter().enumerate() {
+        println!(
+            "==> Reproducibility check: {} build (SOURCE_DATE_EPOCH={})",
+            label, epoch
+        );
+        if output.exists() {
+            fs::remove_dir_all(output)?;
+        }
+        let status = Command::new(std::env::current_exe()?)
+            .args(args)
+            .env("SOURCE_DATE_EPOCH", &epoch)
+            .status()?;
+        if !status.success() {
+            return Err(format!("{} build failed ({})", label, status).into());
+        }
+        if round == 0 {
+            if first.exists() {
+                fs::remove_dir_all(first)?;
+            }
+            fs::rename(output, first)?;
+        }
+    }
+
+    let differences = linux_bundler::diff_outputs(first, output)?;
+    if differences.is_empty() {
+        println!("Builds are reproducible");
+        return Ok(());
+    }
+    for difference in &differences {
+        println!("  {}", difference);
+    }
+    Err(format!("{} output file(s) are not reproducible", differences.len()).into())
+}
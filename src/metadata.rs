@@ -1,16 +1,22 @@
+use crate::arch;
 use sha2::{Digest, Sha256};
+use std::collections::HashMap;
 use std::fs::File;
 use std::io::{BufRead, BufReader, Read};
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Metadata {
     pub name: String,
+    /// Name shared by all packages built from this metadata; equals `name` unless split
+    pub pkgbase: String,
+    pub epoch: String,
     pub version: String,
     pub release: String,
     pub maintainer: String,
     pub email: String,
     pub description: String,
     pub arch: Vec<String>,
+    pub target_arch: String,
     pub url: String,
     pub license: String,
     pub alpm_depends: Vec<String>,
@@ -18,29 +24,78 @@ pub struct Metadata {
     pub rpm_depends: Vec<String>,
     pub provides: Vec<String>,
     pub conflicts: Vec<String>,
+    pub backup: Vec<String>,
+    pub systemd_units: Vec<String>,
     pub sources: Vec<String>,
     pub md5sums: Vec<String>,
     pub sha1sums: Vec<String>,
     pub sha256sums: Vec<String>,
     pub sha512sums: Vec<String>,
     pub alpm_build_env: Vec<String>,
+    /// makepkg-style build options, e.g. `debug` or `!strip`; see `has_option`
+    pub options: Vec<String>,
+    /// Staged paths (or directories) whose ELF files are not stripped
+    pub strip_exclude: Vec<String>,
+    /// File mapping sonames to ALPM and deb packages, see `autodeps`
+    pub soname_map: String,
+    /// Write a deb `shlibs` file for the staged shared libraries
+    pub deb_shlibs: bool,
+    /// SVG application icon, relative to the source directory, see `icon::render`
+    pub icon: String,
     pub appimage_exec: String,
-    pub package_instructions: Vec<String>,
-    pub appimage_icon_instructions: Vec<String>,
-    pub appimage_desktop_instructions: Vec<String>,
+    /// Copy the shared libraries the AppDir links against into it, like linuxdeploy
+    pub appimage_bundle_libs: bool,
+    /// Root the bundled libraries are resolved in; `/` when empty
+    pub appimage_sysroot: String,
+    /// Sonames never bundled, on top of the standard exclude list
+    pub appimage_lib_exclude: Vec<String>,
+    /// AppImageUpdate transport string written to the runtime's `.upd_info` section
+    pub appimage_update_info: String,
+    /// ASCII-armored OpenPGP secret key the AppImage is signed with
+    pub appimage_signing_key: String,
+    /// AppImage runtime used instead of the embedded one for the target architecture
+    pub appimage_runtime: String,
+    pub alpm_compression: String,
+    pub deb_compression: String,
+    pub rpm_compression: String,
+    pub appimage_compression: String,
+    pub package_instructions: Vec<(usize, String)>,
+    pub appimage_icon_instructions: Vec<(usize, String)>,
+    pub appimage_desktop_instructions: Vec<(usize, String)>,
+    pub users: Vec<(usize, String)>,
+    /// `Key = value` lines of the `desktop:` block, see `desktop::generate`
+    pub desktop: Vec<(usize, String)>,
+    /// `key = value` lines of the `appstream:` block, see `appstream::generate`
+    pub appstream: Vec<(usize, String)>,
+    /// Maintainer script bodies; empty when the block is not given
+    pub pre_install: String,
+    pub post_install: String,
+    pub pre_upgrade: String,
+    pub post_upgrade: String,
+    pub pre_remove: String,
+    pub post_remove: String,
     pub pkgbuild_sha256sum: String,
+    /// Split packages from the `packages:` section, empty for a single package
+    pub packages: Vec<Metadata>,
+    /// Set on the entries of `packages`
+    pub is_split: bool,
+    /// Set on the generated packages holding detached debug symbols
+    pub is_debug: bool,
 }
 
 impl Default for Metadata {
     fn default() -> Self {
         Self {
             name: String::new(),
+            pkgbase: String::new(),
+            epoch: String::new(),
             version: String::new(),
             release: String::new(),
             maintainer: String::new(),
             email: String::new(),
             description: String::new(),
             arch: Vec::new(),
+            target_arch: String::new(),
             url: String::new(),
             license: String::new(),
             alpm_depends: Vec::new(),
@@ -48,6 +103,8 @@ impl Default for Metadata {
             rpm_depends: Vec::new(),
             provides: Vec::new(),
             conflicts: Vec::new(),
+            backup: Vec::new(),
+            systemd_units: Vec::new(),
             sources: Vec::new(),
             md5sums: Vec::new(),
             sha1sums: Vec::new(),
@@ -60,16 +117,141 @@ impl Default for Metadata {
                 "check".to_string(),
                 "!sign".to_string(),
             ],
+            options: Vec::new(),
+            strip_exclude: Vec::new(),
+            soname_map: String::new(),
+            deb_shlibs: false,
+            icon: String::new(),
             appimage_exec: String::new(),
+            appimage_bundle_libs: false,
+            appimage_sysroot: String::new(),
+            appimage_lib_exclude: Vec::new(),
+            appimage_update_info: String::new(),
+            appimage_signing_key: String::new(),
+            appimage_runtime: String::new(),
+            alpm_compression: String::new(),
+            deb_compression: String::new(),
+            rpm_compression: String::new(),
+            appimage_compression: String::new(),
             package_instructions: Vec::new(),
             appimage_icon_instructions: Vec::new(),
             appimage_desktop_instructions: Vec::new(),
+            users: Vec::new(),
+            desktop: Vec::new(),
+            appstream: Vec::new(),
+            pre_install: String::new(),
+            post_install: String::new(),
+            pre_upgrade: String::new(),
+            post_upgrade: String::new(),
+            pre_remove: String::new(),
+            post_remove: String::new(),
             pkgbuild_sha256sum: String::new(),
+            packages: Vec::new(),
+            is_split: false,
+            is_debug: false,
         }
     }
 }
 
+impl Metadata {
+    /// The binary packages to build: the split packages if any, otherwise this package.
+    pub fn split_packages(&self) -> Vec<&Metadata> {
+        if self.packages.is_empty() {
+            vec![self]
+        } else {
+            self.packages.iter().collect()
+        }
+    }
+
+    /// Whether a build option is enabled: `name` turns it on and `!name` off, and the last
+    /// mention wins. Unlisted options keep their default, as with makepkg's `OPTIONS`.
+    pub fn has_option(&self, name: &str) -> bool {
+        self.options
+            .iter()
+            .rev()
+            .find_map(|option| match option.strip_prefix('!') {
+                Some(disabled) if disabled == name => Some(false),
+                None if option == name => Some(true),
+                _ => None,
+            })
+            .unwrap_or(DEFAULT_OPTIONS.contains(&name))
+    }
+}
+
+/// Options understood in `options`.
+const KNOWN_OPTIONS: &[&str] = &["autodeps", "debug", "strip"];
+
+/// Options enabled unless disabled with `!name`.
+const DEFAULT_OPTIONS: &[&str] = &["strip"];
+
+/// Keys that may be given per architecture, e.g. `sources_aarch64` or `deb_depends_armv7h`.
+/// Per-architecture values are appended to the global ones when building for that arch.
+const ARCH_SPECIFIC_KEYS: &[&str] = &[
+    "sources",
+    "md5sums",
+    "sha1sums",
+    "sha256sums",
+    "sha512sums",
+    "alpm_depends",
+    "deb_depends",
+    "rpm_depends",
+    "provides",
+    "conflicts",
+];
+
+/// Blocks holding install scripts, which are read as written.
+const SCRIPT_BLOCKS: &[&str] = &[
+    "pre_install",
+    "post_install",
+    "pre_upgrade",
+    "post_upgrade",
+    "pre_remove",
+    "post_remove",
+];
+
+/// Keys a split package in the `packages:` section may override; the rest are shared.
+const PACKAGE_KEYS: &[&str] = &[
+    "description",
+    "url",
+    "license",
+    "alpm_depends",
+    "deb_depends",
+    "rpm_depends",
+    "provides",
+    "conflicts",
+    "backup",
+    "systemd_units",
+    "options",
+    "strip_exclude",
+    "deb_shlibs",
+    "icon",
+];
+
+/// Blocks a split package in the `packages:` section may define.
+const PACKAGE_BLOCKS: &[&str] = &[
+    "package",
+    "users",
+    "desktop",
+    "appstream",
+    "pre_install",
+    "post_install",
+    "pre_upgrade",
+    "post_upgrade",
+    "pre_remove",
+    "post_remove",
+];
+
+/// Extract metadata for the first architecture listed in `arch`.
 pub fn extract_metadata(metadata_path: &str) -> Result<Metadata, Box<dyn std::error::Error>> {
+    extract_metadata_for_arch(metadata_path, None)
+}
+
+/// Extract metadata for `target_arch` (or the first listed architecture), merging in the
+/// `<key>_<arch>` values for that architecture.
+pub fn extract_metadata_for_arch(
+    metadata_path: &str,
+    target_arch: Option<&str>,
+) -> Result<Metadata, Box<dyn std::error::Error>> {
     let mut metadata_file = File::open(metadata_path)?;
 
     // Calculate SHA256 of the metadata file
@@ -81,68 +263,40 @@ pub fn extract_metadata(metadata_path: &str) -> Result<Metadata, Box<dyn std::er
 
     let reader = BufReader::new(&buffer[..]);
 
-    let mut metadata = Metadata::default();
-    metadata.pkgbuild_sha256sum = pkgbuild_sha256sum;
-    let mut lines = reader.lines();
-    while let Some(line) = lines.next() {
-        let line = line?;
-        let trimmed = line.trim();
+    // First pass: collect raw values so variables can be used before they are defined.
+    // Line numbers are kept alongside values so errors can point at the right line.
+    let mut lines = reader.lines().enumerate();
+    let section = read_section(&mut lines, false)?;
 
-        if trimmed.is_empty() || trimmed.starts_with('#') {
-            continue;
+    let arches = section
+        .scalars
+        .iter()
+        .filter(|(_, key, _)| key == "arch")
+        .map(|(_, _, value)| parse_array(value))
+        .next_back()
+        .unwrap_or_default();
+    for arch in &arches {
+        arch::lookup(arch)?;
+    }
+    let target_arch = match target_arch {
+        Some(arch) if !arches.iter().any(|a| a == arch) => {
+            return Err(
+                format!("Architecture '{}' is not listed in arch {:?}", arch, arches).into(),
+            );
         }
+        Some(arch) => arch.to_string(),
+        None => arches.first().cloned().unwrap_or_default(),
+    };
 
-        if let Some((key, value)) = trimmed.split_once(':') {
-            let key = key.trim();
-            let value = value.trim();
-
-            if value == "{" {
-                let mut block_lines = Vec::new();
-                while let Some(block_line) = lines.next() {
-                    let block_line = block_line?;
-                    let trimmed_block = block_line.trim();
-                    if trimmed_block == "}" {
-                        break;
-                    }
-                    if !trimmed_block.is_empty() && !trimmed_block.starts_with('#') {
-                        block_lines.push(trimmed_block.to_string());
-                    }
-                }
-                match key {
-                    "package" => metadata.package_instructions = block_lines,
-                    "appimage_icon" => metadata.appimage_icon_instructions = block_lines,
-                    "appimage_desktop" => metadata.appimage_desktop_instructions = block_lines,
-                    _ => {}
-                }
-                continue;
-            }
+    let variables = Variables::new(&section.scalars, &section.blocks, &target_arch)?;
 
-            match key {
-                "name" => metadata.name = value.to_string(),
-                "version" => metadata.version = value.to_string(),
-                "release" => metadata.release = value.to_string(),
-                "maintainer" => metadata.maintainer = value.to_string(),
-                "email" => metadata.email = value.to_string(),
-                "description" => metadata.description = value.to_string(),
-                "arch" => metadata.arch = parse_array(value),
-                "url" => metadata.url = value.to_string(),
-                "license" => metadata.license = value.to_string(),
-                "alpm_depends" => metadata.alpm_depends = parse_array(value),
-                "deb_depends" => metadata.deb_depends = parse_array(value),
-                "rpm_depends" => metadata.rpm_depends = parse_array(value),
-                "provides" => metadata.provides = parse_array(value),
-                "conflicts" => metadata.conflicts = parse_array(value),
-                "sources" => metadata.sources = parse_array(value),
-                "md5sums" => metadata.md5sums = parse_array(value),
-                "sha1sums" => metadata.sha1sums = parse_array(value),
-                "sha256sums" => metadata.sha256sums = parse_array(value),
-                "sha512sums" => metadata.sha512sums = parse_array(value),
-                "alpm_build_env" => metadata.alpm_build_env = parse_array(value),
-                "appimage_exec" => metadata.appimage_exec = value.trim_matches('"').to_string(),
-                _ => {}
-            }
-        }
-    }
+    let mut metadata = Metadata {
+        pkgbuild_sha256sum,
+        target_arch,
+        ..Default::default()
+    };
+    apply_section(&mut metadata, &section, &variables, &arches, false)?;
+    metadata.pkgbase = metadata.name.clone();
 
     if metadata.name.is_empty() {
         return Err("Package name not found".into());
@@ -156,6 +310,10 @@ pub fn extract_metadata(metadata_path: &str) -> Result<Metadata, Box<dyn std::er
         return Err("Package release not found".into());
     }
 
+    if !metadata.epoch.is_empty() && metadata.epoch.parse::<u32>().is_err() {
+        return Err(format!("Package epoch must be a number: {}", metadata.epoch).into());
+    }
+
     if metadata.maintainer.is_empty() {
         return Err("Package maintainer not found".into());
     }
@@ -210,9 +368,528 @@ pub fn extract_metadata(metadata_path: &str) -> Result<Metadata, Box<dyn std::er
         );
     }
 
+    // Split packages inherit every field of the base package except its contents
+    if !section.packages.is_empty() && !metadata.package_instructions.is_empty() {
+        return Err(
+            "The top-level package block cannot be combined with packages; \
+                    give each split package its own package block"
+                .into(),
+        );
+    }
+    for (block, lines) in [
+        ("desktop", &metadata.desktop),
+        ("appstream", &metadata.appstream),
+    ] {
+        if !section.packages.is_empty() && !lines.is_empty() {
+            return Err(format!(
+                "The top-level {} block cannot be combined with packages; \
+                    move it into the split package it describes",
+                block
+            )
+            .into());
+        }
+    }
+    // Every split package would install the same icon into the hicolor theme
+    if !section.packages.is_empty() && !metadata.icon.is_empty() {
+        return Err("The top-level icon cannot be combined with packages; \
+                    set it in the split package it belongs to"
+            .into());
+    }
+    for (line_no, name, package_section) in &section.packages {
+        if metadata.packages.iter().any(|p| &p.name == name) {
+            return Err(format!("line {}: package '{}' is defined twice", line_no, name).into());
+        }
+        let mut package = Metadata {
+            name: name.clone(),
+            pkgbase: metadata.pkgbase.clone(),
+            backup: Vec::new(),
+            systemd_units: Vec::new(),
+            users: Vec::new(),
+            pre_install: String::new(),
+            post_install: String::new(),
+            pre_upgrade: String::new(),
+            post_upgrade: String::new(),
+            pre_remove: String::new(),
+            post_remove: String::new(),
+            packages: Vec::new(),
+            is_split: true,
+            ..metadata.clone()
+        };
+        apply_section(&mut package, package_section, &variables, &arches, true)?;
+        metadata.packages.push(package);
+    }
+
+    let options = metadata.packages.iter().flat_map(|p| &p.options);
+    for option in metadata.options.iter().chain(options) {
+        if !KNOWN_OPTIONS.contains(&option.trim_start_matches('!')) {
+            return Err(format!("Unknown option '{}'", option).into());
+        }
+    }
+
     Ok(metadata)
 }
 
+/// Raw contents of the metadata file or of one entry of its `packages:` section.
+#[derive(Default)]
+struct Section {
+    scalars: Vec<(usize, String, String)>,
+    blocks: Vec<(String, Vec<(usize, String)>)>,
+    packages: Vec<(usize, String, Section)>,
+}
+
+/// Read `key: value` lines and `key: {` ... `}` blocks up to the end of the file, or up to
+/// the closing `}` of a split package when `nested` is set.
+fn read_section(
+    lines: &mut impl Iterator<Item = (usize, std::io::Result<String>)>,
+    nested: bool,
+) -> Result<Section, Box<dyn std::error::Error>> {
+    let mut section = Section::default();
+
+    while let Some((index, line)) = lines.next() {
+        let line = line?;
+        let trimmed = line.trim();
+
+        if trimmed.is_empty() || trimmed.starts_with('#') {
+            continue;
+        }
+
+        if nested && trimmed == "}" {
+            return Ok(section);
+        }
+
+        if let Some((key, value)) = trimmed.split_once(':') {
+            let key = key.trim();
+            let value = value.trim();
+
+            if value == "{" && key == "packages" && !nested {
+                read_packages(lines, &mut section)?;
+                continue;
+            }
+
+            if value == "{" && SCRIPT_BLOCKS.contains(&key) {
+                let script = read_script(lines, index + 1, key)?;
+                section.blocks.push((key.to_string(), script));
+                continue;
+            }
+
+            if value == "{" {
+                let mut block_lines = Vec::new();
+                for (index, block_line) in lines.by_ref() {
+                    let block_line = block_line?;
+                    let trimmed_block = block_line.trim();
+                    if trimmed_block == "}" {
+                        break;
+                    }
+                    if !trimmed_block.is_empty() && !trimmed_block.starts_with('#') {
+                        block_lines.push((index + 1, trimmed_block.to_string()));
+                    }
+                }
+                section.blocks.push((key.to_string(), block_lines));
+                continue;
+            }
+
+            section
+                .scalars
+                .push((index + 1, key.to_string(), value.to_string()));
+        } else {
+            return Err(format!(
+                "line {}: expected `key: value` or `key: {{`: {}",
+                index + 1,
+                trimmed
+            )
+            .into());
+        }
+    }
+
+    if nested {
+        return Err("Unterminated package in packages section".into());
+    }
+    Ok(section)
+}
+
+/// Read the body of the install script block `key` opened on `line_no` as written, up to the
+/// `}` that balances its opening brace. Comments and relative indentation are kept; only the
+/// indentation common to all lines is removed so that heredoc terminators still match.
+fn read_script(
+    lines: &mut impl Iterator<Item = (usize, std::io::Result<String>)>,
+    line_no: usize,
+    key: &str,
+) -> Result<Vec<(usize, String)>, Box<dyn std::error::Error>> {
+    let mut script: Vec<(usize, String)> = Vec::new();
+    let mut depth = 0;
+    loop {
+        let Some((index, line)) = lines.next() else {
+            return Err(format!("line {}: unterminated '{}' block", line_no, key).into());
+        };
+        let line = line?;
+        if depth == 0 && line.trim() == "}" {
+            break;
+        }
+        depth = (depth + brace_delta(&line)).max(0);
+        script.push((index + 1, line));
+    }
+
+    while script
+        .last()
+        .is_some_and(|(_, line)| line.trim().is_empty())
+    {
+        script.pop();
+    }
+    let indent = script
+        .iter()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(_, line)| line.len() - line.trim_start().len())
+        .min()
+        .unwrap_or(0);
+    Ok(script
+        .into_iter()
+        .skip_while(|(_, line)| line.trim().is_empty())
+        .map(|(index, line)| {
+            let dedented = line.get(indent..).unwrap_or_else(|| line.trim_start());
+            (index, dedented.to_string())
+        })
+        .collect())
+}
+
+/// How many braces a shell line opens minus how many it closes, ignoring quoted braces and
+/// comments.
+fn brace_delta(line: &str) -> i32 {
+    let mut delta = 0;
+    let mut quote = None;
+    let mut escaped = false;
+    let mut word_start = true;
+    for c in line.chars() {
+        if escaped {
+            escaped = false;
+        } else if let Some(q) = quote {
+            if c == q {
+                quote = None;
+            } else if c == '\\' && q == '"' {
+                escaped = true;
+            }
+        } else {
+            match c {
+                '\\' => escaped = true,
+                '\'' | '"' => quote = Some(c),
+                '#' if word_start => break,
+                '{' => delta += 1,
+                '}' => delta -= 1,
+                _ => {}
+            }
+        }
+        word_start = c.is_whitespace() || c == ';';
+    }
+    delta
+}
+
+/// Read the entries of a `packages:` section, each written as `name: {` ... `}`.
+fn read_packages(
+    lines: &mut impl Iterator<Item = (usize, std::io::Result<String>)>,
+    section: &mut Section,
+) -> Result<(), Box<dyn std::error::Error>> {
+    while let Some((index, line)) = lines.next() {
+        let line = line?;
+        let trimmed = line.trim();
+
+        if trimmed.is_empty() || trimmed.starts_with('#') {
+            continue;
+        }
+        if trimmed == "}" {
+            return Ok(());
+        }
+
+        match trimmed.split_once(':') {
+            Some((name, "{")) | Some((name, " {")) => {
+                let name = name.trim().to_string();
+                let package = read_section(lines, true)?;
+                section.packages.push((index + 1, name, package));
+            }
+            _ => {
+                return Err(format!(
+                    "line {}: expected `name: {{` in packages: {}",
+                    index + 1,
+                    trimmed
+                )
+                .into());
+            }
+        }
+    }
+
+    Err("Unterminated packages section".into())
+}
+
+/// Assign the values of `section` to `metadata`. Split packages (`nested`) may only set the
+/// fields that describe a single binary package.
+fn apply_section(
+    metadata: &mut Metadata,
+    section: &Section,
+    variables: &Variables,
+    arches: &[String],
+    nested: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut arch_values: Vec<(&str, Vec<String>)> = Vec::new();
+    for (line_no, key, value) in &section.scalars {
+        let value = variables.expand(value, *line_no)?;
+        let value = value.as_str();
+
+        let base_key = ARCH_SPECIFIC_KEYS
+            .iter()
+            .find(|base| {
+                key.strip_prefix(**base)
+                    .is_some_and(|rest| rest.starts_with('_'))
+            })
+            .copied()
+            .unwrap_or(key);
+        if nested && !PACKAGE_KEYS.contains(&base_key) {
+            return Err(format!("line {}: '{}' cannot be set per package", line_no, key).into());
+        }
+
+        // `<key>_<arch>`: keep values for the target architecture, reject unknown arches
+        if let Some((base, arch)) = ARCH_SPECIFIC_KEYS.iter().find_map(|base| {
+            let arch = key.strip_prefix(base)?.strip_prefix('_')?;
+            Some((*base, arch))
+        }) {
+            if !arches.iter().any(|a| a == arch) {
+                return Err(format!(
+                    "line {}: '{}' refers to architecture '{}' which is not listed in arch",
+                    line_no, key, arch
+                )
+                .into());
+            }
+            if arch == metadata.target_arch {
+                arch_values.push((base, parse_array(value)));
+            }
+            continue;
+        }
+
+        match key.as_str() {
+            "name" => metadata.name = value.to_string(),
+            "epoch" => metadata.epoch = value.to_string(),
+            "version" => metadata.version = value.to_string(),
+            "release" => metadata.release = value.to_string(),
+            "maintainer" => metadata.maintainer = value.to_string(),
+            "email" => metadata.email = value.to_string(),
+            "description" => metadata.description = value.to_string(),
+            "arch" => metadata.arch = parse_array(value),
+            "url" => metadata.url = value.to_string(),
+            "license" => metadata.license = value.to_string(),
+            "alpm_depends" => metadata.alpm_depends = parse_array(value),
+            "deb_depends" => metadata.deb_depends = parse_array(value),
+            "rpm_depends" => metadata.rpm_depends = parse_array(value),
+            "provides" => metadata.provides = parse_array(value),
+            "conflicts" => metadata.conflicts = parse_array(value),
+            "backup" => metadata.backup = parse_array(value),
+            "systemd_units" => metadata.systemd_units = parse_array(value),
+            "sources" => metadata.sources = parse_array(value),
+            "md5sums" => metadata.md5sums = parse_array(value),
+            "sha1sums" => metadata.sha1sums = parse_array(value),
+            "sha256sums" => metadata.sha256sums = parse_array(value),
+            "sha512sums" => metadata.sha512sums = parse_array(value),
+            "alpm_build_env" => metadata.alpm_build_env = parse_array(value),
+            "options" => metadata.options = parse_array(value),
+            "strip_exclude" => metadata.strip_exclude = parse_array(value),
+            "soname_map" => metadata.soname_map = value.trim_matches('"').to_string(),
+            "deb_shlibs" => metadata.deb_shlibs = parse_bool(key, value, *line_no)?,
+            "icon" => metadata.icon = value.trim_matches('"').to_string(),
+            "appimage_exec" => metadata.appimage_exec = value.trim_matches('"').to_string(),
+            "appimage_bundle_libs" => {
+                metadata.appimage_bundle_libs = parse_bool(key, value, *line_no)?
+            }
+            "appimage_sysroot" => metadata.appimage_sysroot = value.trim_matches('"').to_string(),
+            "appimage_lib_exclude" => metadata.appimage_lib_exclude = parse_array(value),
+            "appimage_runtime" => metadata.appimage_runtime = value.trim_matches('"').to_string(),
+            "appimage_signing_key" => {
+                metadata.appimage_signing_key = value.trim_matches('"').to_string()
+            }
+            "appimage_update_info" => {
+                metadata.appimage_update_info = value.trim_matches('"').to_string()
+            }
+            "alpm_compression" => metadata.alpm_compression = value.to_string(),
+            "deb_compression" => metadata.deb_compression = value.to_string(),
+            "rpm_compression" => metadata.rpm_compression = value.to_string(),
+            "appimage_compression" => metadata.appimage_compression = value.to_string(),
+            _ => {}
+        }
+    }
+
+    for (base, values) in arch_values {
+        let field = match base {
+            "sources" => &mut metadata.sources,
+            "md5sums" => &mut metadata.md5sums,
+            "sha1sums" => &mut metadata.sha1sums,
+            "sha256sums" => &mut metadata.sha256sums,
+            "sha512sums" => &mut metadata.sha512sums,
+            "alpm_depends" => &mut metadata.alpm_depends,
+            "deb_depends" => &mut metadata.deb_depends,
+            "rpm_depends" => &mut metadata.rpm_depends,
+            "provides" => &mut metadata.provides,
+            "conflicts" => &mut metadata.conflicts,
+            _ => continue,
+        };
+        field.extend(values);
+    }
+
+    for (key, block_lines) in &section.blocks {
+        if nested && !PACKAGE_BLOCKS.contains(&key.as_str()) {
+            let line_no = block_lines.first().map(|(n, _)| *n).unwrap_or_default();
+            return Err(format!("line {}: '{}' cannot be set per package", line_no, key).into());
+        }
+        // Install scripts are shell code and go into the package verbatim; `$` there belongs
+        // to the shell
+        if let Some(script) = script_field(metadata, key) {
+            *script = block_lines
+                .iter()
+                .map(|(_, line)| line.as_str())
+                .collect::<Vec<_>>()
+                .join("\n");
+            continue;
+        }
+        let block_lines = block_lines
+            .iter()
+            .map(|(line_no, line)| Ok((*line_no, variables.expand(line, *line_no)?)))
+            .collect::<Result<Vec<_>, Box<dyn std::error::Error>>>()?;
+        match key.as_str() {
+            "package" => metadata.package_instructions = block_lines,
+            "appimage_icon" => metadata.appimage_icon_instructions = block_lines,
+            "appimage_desktop" => metadata.appimage_desktop_instructions = block_lines,
+            "users" => metadata.users = block_lines,
+            "desktop" => metadata.desktop = block_lines,
+            "appstream" => metadata.appstream = block_lines,
+            _ => {}
+        }
+    }
+
+    Ok(())
+}
+
+/// The install script field set by the block `key`, if it is one.
+fn script_field<'a>(metadata: &'a mut Metadata, key: &str) -> Option<&'a mut String> {
+    match key {
+        "pre_install" => Some(&mut metadata.pre_install),
+        "post_install" => Some(&mut metadata.post_install),
+        "pre_upgrade" => Some(&mut metadata.pre_upgrade),
+        "post_upgrade" => Some(&mut metadata.post_upgrade),
+        "pre_remove" => Some(&mut metadata.pre_remove),
+        "post_remove" => Some(&mut metadata.post_remove),
+        _ => None,
+    }
+}
+
+/// Values available as `${...}` in scalar values, arrays and block lines other than install
+/// scripts: `name`, `epoch`, `version`, `release`, `arch` (the architecture being built),
+/// `srcdir` (where sources are unpacked) and anything declared in a `variables: { key = value }`
+/// block.
+struct Variables {
+    raw: HashMap<String, (usize, String)>,
+}
+
+impl Variables {
+    fn new(
+        scalars: &[(usize, String, String)],
+        blocks: &[(String, Vec<(usize, String)>)],
+        target_arch: &str,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut raw = HashMap::new();
+
+        for (line_no, key, value) in scalars {
+            match key.as_str() {
+                "name" | "epoch" | "version" | "release" => {
+                    raw.insert(key.clone(), (*line_no, value.clone()));
+                }
+                // `${arch}` is the architecture being built, not the whole list
+                "arch" => {
+                    raw.insert(key.clone(), (*line_no, target_arch.to_string()));
+                }
+                _ => {}
+            }
+        }
+
+        for (_, block_lines) in blocks.iter().filter(|(key, _)| key == "variables") {
+            for (line_no, line) in block_lines {
+                let (key, value) = line
+                    .split_once('=')
+                    .ok_or_else(|| format!("line {}: expected `key = value`: {}", line_no, line))?;
+                let key = key.trim();
+                if key.is_empty() || !key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+                    return Err(format!("line {}: invalid variable name '{}'", line_no, key).into());
+                }
+                if ["name", "epoch", "version", "release", "arch", "srcdir"].contains(&key) {
+                    return Err(
+                        format!("line {}: '{}' is a built-in variable", line_no, key).into(),
+                    );
+                }
+                raw.insert(key.to_string(), (*line_no, value.trim().to_string()));
+            }
+        }
+
+        // Sources are unpacked into a directory named after the package
+        if let Some((line_no, name)) = raw.get("name") {
+            let cwd = std::env::current_dir()?;
+            let srcdir = format!("{}/{}", cwd.to_string_lossy().replace('$', "$$"), name);
+            raw.insert("srcdir".to_string(), (*line_no, srcdir));
+        }
+
+        Ok(Self { raw })
+    }
+
+    /// Expand `${var}` references in `value`; `$$` yields a literal `$`.
+    fn expand(&self, value: &str, line_no: usize) -> Result<String, Box<dyn std::error::Error>> {
+        self.expand_inner(value, line_no, &mut Vec::new())
+    }
+
+    fn expand_inner(
+        &self,
+        value: &str,
+        line_no: usize,
+        stack: &mut Vec<String>,
+    ) -> Result<String, Box<dyn std::error::Error>> {
+        let mut expanded = String::new();
+        let mut rest = value;
+
+        while let Some(pos) = rest.find('$') {
+            expanded.push_str(&rest[..pos]);
+            rest = &rest[pos + 1..];
+
+            if let Some(after) = rest.strip_prefix('$') {
+                expanded.push('$');
+                rest = after;
+                continue;
+            }
+
+            let Some(after) = rest.strip_prefix('{') else {
+                return Err(format!("line {}: expected '{{' after '$': {}", line_no, value).into());
+            };
+            let end = after
+                .find('}')
+                .ok_or_else(|| format!("line {}: unterminated '${{': {}", line_no, value))?;
+            let name = &after[..end];
+            rest = &after[end + 1..];
+
+            if stack.iter().any(|n| n == name) {
+                return Err(format!(
+                    "line {}: variable '{}' refers to itself ({} -> {})",
+                    line_no,
+                    name,
+                    stack.join(" -> "),
+                    name
+                )
+                .into());
+            }
+            let (def_line, raw) = self
+                .raw
+                .get(name)
+                .ok_or_else(|| format!("line {}: undefined variable '${{{}}}'", line_no, name))?;
+
+            stack.push(name.to_string());
+            expanded.push_str(&self.expand_inner(raw, *def_line, stack)?);
+            stack.pop();
+        }
+        expanded.push_str(rest);
+
+        Ok(expanded)
+    }
+}
+
 fn parse_array(value: &str) -> Vec<String> {
     value
         .trim_start_matches('[')
@@ -223,24 +900,163 @@ fn parse_array(value: &str) -> Vec<String> {
         .collect()
 }
 
-pub fn print_metadata(metadata_path: &str) -> Result<(), Box<dyn std::error::Error>> {
-    let metadata = extract_metadata(metadata_path)?;
-
-    println!("Package: {}", metadata.name);
-    println!("Version: {}", metadata.version);
-    println!("Release: {}", metadata.release);
-    println!("Maintainer: {}", metadata.maintainer);
-    println!("Description: {}", metadata.description);
-    println!("Arch: {:?}", metadata.arch);
-    println!("URL: {}", metadata.url);
-    println!("License: {}", metadata.license);
-    println!("ALPM Depends: {:?}", metadata.alpm_depends);
-    println!("DEB Depends: {:?}", metadata.deb_depends);
-    println!("RPM Depends: {:?}", metadata.rpm_depends);
-    println!("Provides: {:?}", metadata.provides);
-    println!("Conflicts: {:?}", metadata.conflicts);
-    println!("Sources: {:?}", metadata.sources);
-    println!("SHA256SUMS: {:?}", metadata.sha256sums);
+fn parse_bool(key: &str, value: &str, line_no: usize) -> Result<bool, Box<dyn std::error::Error>> {
+    match value {
+        "true" => Ok(true),
+        "false" => Ok(false),
+        _ => Err(format!("line {}: {} must be true or false: {}", line_no, key, value).into()),
+    }
+}
 
-    Ok(())
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn variables(definitions: &[(&str, &str)]) -> Variables {
+        let raw = definitions
+            .iter()
+            .enumerate()
+            .map(|(index, (key, value))| (key.to_string(), (index + 1, value.to_string())))
+            .collect();
+        Variables { raw }
+    }
+
+    #[test]
+    fn expand_references() {
+        let variables = variables(&[
+            ("name", "hello"),
+            ("version", "1.2"),
+            ("tarball", "${name}-${version}.tar.gz"),
+        ]);
+        for (value, expected) in [
+            ("plain", "plain"),
+            ("${name}", "hello"),
+            ("${tarball}", "hello-1.2.tar.gz"),
+            ("$$", "$"),
+            ("$${name}", "${name}"),
+            ("cost: $$5 for ${name}", "cost: $5 for hello"),
+        ] {
+            assert_eq!(variables.expand(value, 1).unwrap(), expected, "{}", value);
+        }
+    }
+
+    #[test]
+    fn expand_errors() {
+        let variables = variables(&[("a", "${b}"), ("b", "${a}"), ("c", "${c}")]);
+        for (value, message) in [
+            ("${missing}", "undefined variable '${missing}'"),
+            ("${a}", "variable 'a' refers to itself (a -> b -> a)"),
+            ("${c}", "variable 'c' refers to itself (c -> c)"),
+            ("$HOME", "expected '{' after '$'"),
+            ("${name", "unterminated '${'"),
+        ] {
+            let error = variables.expand(value, 7).unwrap_err().to_string();
+            assert!(error.contains(message), "{}: {}", value, error);
+        }
+    }
+
+    fn read(contents: &str) -> Result<Section, Box<dyn std::error::Error>> {
+        let mut lines = contents
+            .lines()
+            .map(|line| Ok(line.to_string()))
+            .enumerate();
+        read_section(&mut lines, false)
+    }
+
+    const SCRIPT_METADATA: &str = r#"name: hello
+post_install: {
+    # Reload units
+    reload() {
+        systemctl daemon-reload || echo '}'
+    }
+
+    reload
+    cat > "$HOME/.hello" <<EOF
+    uid=$(id -u)
+    EOF
+}
+version: 1.0
+"#;
+
+    #[test]
+    fn script_blocks_are_raw() {
+        let section = read(SCRIPT_METADATA).unwrap();
+        let (key, script) = &section.blocks[0];
+        assert_eq!(key, "post_install");
+        let script: Vec<&str> = script.iter().map(|(_, line)| line.as_str()).collect();
+        assert_eq!(
+            script,
+            [
+                "# Reload units",
+                "reload() {",
+                "    systemctl daemon-reload || echo '}'",
+                "}",
+                "",
+                "reload",
+                "cat > \"$HOME/.hello\" <<EOF",
+                "uid=$(id -u)",
+                "EOF",
+            ]
+        );
+        assert_eq!(section.scalars.len(), 2);
+    }
+
+    const SPLIT_METADATA: &str = r#"name: twincan
+version: 1.0.0
+release: 1
+maintainer: JP-Makers
+description: Twincan
+arch: ["x86_64"]
+url: https://example.com
+license: MIT
+alpm_depends: ["qt6-base"]
+deb_depends: ["qt6-base-dev"]
+rpm_depends: ["qt6-qtbase-gui"]
+provides: ["twincan"]
+conflicts: ["twincan"]
+sources: ["twincan.tar.gz"]
+sha256sums: ["SKIP"]
+"#;
+
+    /// Parse `contents` as a metadata file.
+    fn extract(name: &str, contents: &str) -> Result<Metadata, Box<dyn std::error::Error>> {
+        let path = std::env::temp_dir().join(format!("{}-{}", name, std::process::id()));
+        std::fs::write(&path, contents)?;
+        let result = extract_metadata(path.to_str().unwrap());
+        std::fs::remove_file(&path)?;
+        result
+    }
+
+    #[test]
+    fn split_package_icons() {
+        let packages =
+            "packages: {\n  twincan: {\n    icon: twincan.svg\n  }\n  twincan-cli: {\n  }\n}\n";
+        let metadata = extract("split-icons", &format!("{}{}", SPLIT_METADATA, packages)).unwrap();
+        let icons: Vec<&str> = metadata.packages.iter().map(|p| p.icon.as_str()).collect();
+        assert_eq!(icons, ["twincan.svg", ""]);
+
+        let contents = format!("{}icon: twincan.svg\n{}", SPLIT_METADATA, packages);
+        let error = extract("split-icon-error", &contents)
+            .unwrap_err()
+            .to_string();
+        assert!(
+            error.contains("top-level icon cannot be combined"),
+            "{}",
+            error
+        );
+    }
+
+    #[test]
+    fn section_errors() {
+        for (contents, message) in [
+            (
+                "post_remove: {\n  f() {\n  }\n",
+                "line 1: unterminated 'post_remove' block",
+            ),
+            ("name: hello\nstray line\n", "line 2: expected `key: value`"),
+        ] {
+            let error = read(contents).err().unwrap().to_string();
+            assert!(error.contains(message), "{}", error);
+        }
+    }
 }
@@ -0,0 +1,115 @@
+use std::fs;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The `SOURCE_DATE_EPOCH` timestamp, if set.
+/// See <https://reproducible-builds.org/specs/source-date-epoch/>.
+pub fn source_date_epoch() -> Option<u64> {
+    std::env::var("SOURCE_DATE_EPOCH").ok()?.trim().parse().ok()
+}
+
+/// Reject a `SOURCE_DATE_EPOCH` that is set but not a Unix timestamp, rather than
+/// silently building with the current time.
+pub fn check_source_date_epoch() -> Result<(), Box<dyn std::error::Error>> {
+    match std::env::var("SOURCE_DATE_EPOCH") {
+        Ok(value) if source_date_epoch().is_none() => {
+            Err(format!("SOURCE_DATE_EPOCH must be a Unix timestamp: {}", value).into())
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Timestamp recorded as the build date: `SOURCE_DATE_EPOCH` or the current time.
+pub fn build_date() -> u64 {
+    source_date_epoch().unwrap_or_else(|| {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default()
+    })
+}
+
+/// Clamp a file modification time to `SOURCE_DATE_EPOCH`, so files staged during the build
+/// do not carry the time of the build.
+pub fn clamp_mtime(mtime: u64) -> u64 {
+    match source_date_epoch() {
+        Some(epoch) => mtime.min(epoch),
+        None => mtime,
+    }
+}
+
+/// Compare the files of two output directories by name and content and describe every
+/// difference. An empty result means the builds were bit-for-bit identical.
+pub fn diff_outputs(
+    first: &Path,
+    second: &Path,
+) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    let mut differences = Vec::new();
+    let mut names = Vec::new();
+    for dir in [first, second] {
+        for entry in fs::read_dir(dir)? {
+            let entry = entry?;
+            if entry.file_type()?.is_file() && !names.contains(&entry.file_name()) {
+                names.push(entry.file_name());
+            }
+        }
+    }
+    names.sort();
+
+    for name in names {
+        let first_path = first.join(&name);
+        let second_path = second.join(&name);
+        if !second_path.is_file() {
+            differences.push(format!(
+                "{}: only produced by the first build",
+                name.to_string_lossy()
+            ));
+        } else if !first_path.is_file() {
+            differences.push(format!(
+                "{}: only produced by the second build",
+                name.to_string_lossy()
+            ));
+        } else if fs::read(&first_path)? != fs::read(&second_path)? {
+            differences.push(format!("{}: contents differ", name.to_string_lossy()));
+        }
+    }
+
+    Ok(differences)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn diff_reports_both_sides() {
+        let root = std::env::temp_dir().join(format!("diff-outputs-{}", std::process::id()));
+        let (first, second) = (root.join("first"), root.join("second"));
+        for (dir, files) in [
+            (
+                &first,
+                [("same", "a"), ("changed", "1"), ("first-only", "x")],
+            ),
+            (
+                &second,
+                [("same", "a"), ("changed", "2"), ("second-only", "y")],
+            ),
+        ] {
+            fs::create_dir_all(dir).unwrap();
+            for (name, contents) in files {
+                fs::write(dir.join(name), contents).unwrap();
+            }
+        }
+
+        let differences = diff_outputs(&first, &second).unwrap();
+        fs::remove_dir_all(&root).unwrap();
+        assert_eq!(
+            differences,
+            [
+                "changed: contents differ",
+                "first-only: only produced by the first build",
+                "second-only: only produced by the second build",
+            ]
+        );
+    }
+}
@@ -0,0 +1,37 @@
+/// Build a shell script that runs each non-empty body when its condition holds; a body with
+/// an empty condition is included as is. Used by formats that have a single script per phase
+/// and pass the operation as arguments (deb maintainer scripts, rpm scriptlets).
+/// Returns `None` if every body is empty.
+pub fn dispatch(shebang: bool, branches: &[(&str, &str)]) -> Option<String> {
+    if branches.iter().all(|(_, body)| body.is_empty()) {
+        return None;
+    }
+
+    let mut script = String::new();
+    if shebang {
+        script.push_str("#!/bin/sh\n");
+    }
+    script.push_str("set -e\n");
+    for (condition, body) in branches.iter().filter(|(_, body)| !body.is_empty()) {
+        if condition.is_empty() {
+            script.push_str(&format!("{}\n", body));
+        } else {
+            script.push_str(&format!("if {}; then\n{}\nfi\n", condition, body));
+        }
+    }
+    Some(script)
+}
+
+/// Build an ALPM `.INSTALL` file defining one function per non-empty body.
+/// Returns `None` if every body is empty.
+pub fn install_functions(functions: &[(&str, &str)]) -> Option<String> {
+    if functions.iter().all(|(_, body)| body.is_empty()) {
+        return None;
+    }
+
+    let mut script = String::new();
+    for (name, body) in functions.iter().filter(|(_, body)| !body.is_empty()) {
+        script.push_str(&format!("{}() {{\n{}\n}}\n\n", name, body));
+    }
+    Some(script)
+}
@@ -0,0 +1,37 @@
+use crate::elf;
+use crate::metadata::Metadata;
+use std::fs;
+use std::path::Path;
+
+/// With the `strip` option (on by default), strip the debug info and local symbols of the ELF
+/// files staged in `pkg_dir`, except those under a `strip_exclude` path.
+pub fn strip_package(pkg_dir: &str, metadata: &Metadata) -> Result<(), Box<dyn std::error::Error>> {
+    if !metadata.has_option("strip") {
+        return Ok(());
+    }
+
+    let base_dir = Path::new(pkg_dir);
+    for rel_path in elf::staged_elf_files(base_dir)? {
+        let excluded = metadata
+            .strip_exclude
+            .iter()
+            .any(|exclude| rel_path.starts_with(exclude.trim_start_matches('/')));
+        if excluded {
+            println!("Not stripping /{}: excluded", rel_path.display());
+            continue;
+        }
+
+        let path = base_dir.join(&rel_path);
+        // A file the ELF rewriter cannot handle is shipped as it is rather than failing the build
+        match elf::strip(&fs::read(&path)?) {
+            Ok(Some(stripped)) => {
+                fs::write(&path, stripped)?;
+                println!("Stripped /{}", rel_path.display());
+            }
+            Ok(None) => {}
+            Err(e) => println!("Warning: leaving /{} unstripped: {}", rel_path.display(), e),
+        }
+    }
+
+    Ok(())
+}
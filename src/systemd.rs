@@ -0,0 +1,322 @@
+use crate::chmod::split_words;
+use crate::metadata::Metadata;
+use std::path::Path;
+
+pub const UNIT_DIR: &str = "/usr/lib/systemd/system";
+
+/// Path of the generated sysusers.d file.
+pub fn sysusers_path(metadata: &Metadata) -> String {
+    format!("/usr/lib/sysusers.d/{}.conf", metadata.name)
+}
+
+/// Path of the generated tmpfiles.d file.
+pub fn tmpfiles_path(metadata: &Metadata) -> String {
+    format!("/usr/lib/tmpfiles.d/{}.conf", metadata.name)
+}
+
+/// File names of the units listed in `systemd_units`.
+pub fn unit_names(metadata: &Metadata) -> Vec<String> {
+    metadata
+        .systemd_units
+        .iter()
+        .filter_map(|unit| Path::new(unit).file_name())
+        .map(|name| name.to_string_lossy().into_owned())
+        .collect()
+}
+
+/// Translate the `users:` block into sysusers.d and tmpfiles.d contents.
+///
+///   user NAME [-u UID] [-c COMMENT] [-d HOME] [-s SHELL]
+///   group NAME [-g GID]
+///   member USER GROUP
+///   dir PATH [-m MODE] [-o OWNER] [-g GROUP]
+pub fn users_config(metadata: &Metadata) -> Result<(String, String), Box<dyn std::error::Error>> {
+    let mut sysusers = String::new();
+    let mut tmpfiles = String::new();
+
+    for (line_no, line) in &metadata.users {
+        let result = split_words(line).and_then(|words| {
+            let words: Vec<&str> = words.iter().map(String::as_str).collect();
+            match words.as_slice() {
+                ["user", name, rest @ ..] => {
+                    let opts = parse_flags(rest, "ucds")?;
+                    // The comment becomes the GECOS field of /etc/passwd
+                    if let Some(comment) = opts[1]
+                        && comment.chars().any(|c| c == ':' || c.is_control())
+                    {
+                        return Err("The comment may not contain ':' or control characters".into());
+                    }
+                    sysusers.push_str(&format!(
+                        "u {} {} {} {} {}\n",
+                        name,
+                        opts[0].unwrap_or("-"),
+                        opts[1].map_or("-".to_string(), quote_field),
+                        opts[2].unwrap_or("-"),
+                        opts[3].unwrap_or("-")
+                    ));
+                }
+                ["group", name, rest @ ..] => {
+                    let opts = parse_flags(rest, "g")?;
+                    sysusers.push_str(&format!("g {} {}\n", name, opts[0].unwrap_or("-")));
+                }
+                ["member", user, group] => {
+                    sysusers.push_str(&format!("m {} {}\n", user, group));
+                }
+                ["dir", path, rest @ ..] => {
+                    let opts = parse_flags(rest, "mog")?;
+                    tmpfiles.push_str(&format!(
+                        "d {} {} {} {} -\n",
+                        path,
+                        opts[0].unwrap_or("0755"),
+                        opts[1].unwrap_or("root"),
+                        opts[2].unwrap_or("root")
+                    ));
+                }
+                _ => return Err("Expected 'user', 'group', 'member' or 'dir'".into()),
+            }
+            Ok(())
+        });
+        result.map_err(|e| format!("line {}: {}: {}", line_no, e, line))?;
+    }
+
+    Ok((sysusers, tmpfiles))
+}
+
+/// Parse `-X VALUE` pairs, returning the value for each flag in `flags` in order.
+fn parse_flags<'a>(
+    args: &[&'a str],
+    flags: &str,
+) -> Result<Vec<Option<&'a str>>, Box<dyn std::error::Error>> {
+    let mut values = vec![None; flags.len()];
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let index = arg
+            .strip_prefix('-')
+            .filter(|flag| flag.len() == 1)
+            .and_then(|flag| flags.find(flag))
+            .ok_or_else(|| format!("Unknown option '{}'", arg))?;
+        values[index] = Some(
+            *args
+                .next()
+                .ok_or_else(|| format!("Option '{}' requires a value", arg))?,
+        );
+    }
+    Ok(values)
+}
+
+/// Quote a sysusers.d field such as the user comment: `"` and `\` are backslash-escaped and
+/// `%` is doubled since it starts a specifier.
+fn quote_field(value: &str) -> String {
+    let escaped = value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('%', "%%");
+    format!("\"{}\"", escaped)
+}
+
+/// Shell snippet creating the declared users before files owned by them are unpacked.
+pub fn create_users_snippet(metadata: &Metadata) -> Result<String, Box<dyn std::error::Error>> {
+    let (sysusers, _) = users_config(metadata)?;
+    if sysusers.is_empty() {
+        return Ok(String::new());
+    }
+    Ok(format!(
+        "if command -v systemd-sysusers >/dev/null 2>&1; then\n\
+         systemd-sysusers --replace={} - <<'EOF'\n{}EOF\nfi",
+        sysusers_path(metadata),
+        sysusers
+    ))
+}
+
+/// Shell snippet creating the declared directories once the package is unpacked.
+pub fn create_tmpfiles_snippet(metadata: &Metadata) -> Result<String, Box<dyn std::error::Error>> {
+    let (_, tmpfiles) = users_config(metadata)?;
+    if tmpfiles.is_empty() {
+        return Ok(String::new());
+    }
+    Ok(format!(
+        "if command -v systemd-tmpfiles >/dev/null 2>&1; then\n\
+         systemd-tmpfiles --create {} || true\nfi",
+        tmpfiles_path(metadata)
+    ))
+}
+
+/// deb postinst snippet, following what debhelper generates through `deb-systemd-helper`:
+/// enable units on first install (keeping the admin's choice afterwards) and start or restart them.
+pub fn deb_postinst_snippet(metadata: &Metadata) -> String {
+    let units = unit_names(metadata);
+    if units.is_empty() {
+        return String::new();
+    }
+    let mut snippet =
+        String::from("if [ \"$1\" = \"configure\" ] || [ \"$1\" = \"abort-upgrade\" ]; then\n");
+    for unit in &units {
+        snippet.push_str(&format!(
+            "deb-systemd-helper unmask '{0}' >/dev/null || true\n\
+             if deb-systemd-helper --quiet was-enabled '{0}'; then\n\
+             deb-systemd-helper enable '{0}' >/dev/null || true\n\
+             else\n\
+             deb-systemd-helper update-state '{0}' >/dev/null || true\n\
+             fi\n",
+            unit
+        ));
+    }
+    snippet.push_str(&format!(
+        "if [ -d /run/systemd/system ]; then\n\
+         systemctl --system daemon-reload >/dev/null || true\n\
+         if [ -n \"$2\" ]; then action=restart; else action=start; fi\n\
+         deb-systemd-invoke $action {} >/dev/null || true\n\
+         fi\n\
+         fi",
+        quoted_list(&units)
+    ));
+    snippet
+}
+
+/// deb prerm snippet stopping the units when the package is removed.
+pub fn deb_prerm_snippet(metadata: &Metadata) -> String {
+    let units = unit_names(metadata);
+    if units.is_empty() {
+        return String::new();
+    }
+    format!(
+        "if [ -d /run/systemd/system ] && [ \"$1\" = \"remove\" ]; then\n\
+         deb-systemd-invoke stop {} >/dev/null || true\n\
+         fi",
+        quoted_list(&units)
+    )
+}
+
+/// deb postrm snippet masking units on remove and forgetting their state on purge.
+pub fn deb_postrm_snippet(metadata: &Metadata) -> String {
+    let units = unit_names(metadata);
+    if units.is_empty() {
+        return String::new();
+    }
+    let list = quoted_list(&units);
+    format!(
+        "if [ -d /run/systemd/system ]; then\n\
+         systemctl --system daemon-reload >/dev/null || true\n\
+         fi\n\
+         if [ \"$1\" = \"remove\" ] && [ -x /usr/bin/deb-systemd-helper ]; then\n\
+         deb-systemd-helper mask {0} >/dev/null || true\n\
+         fi\n\
+         if [ \"$1\" = \"purge\" ] && [ -x /usr/bin/deb-systemd-helper ]; then\n\
+         deb-systemd-helper purge {0} >/dev/null || true\n\
+         deb-systemd-helper unmask {0} >/dev/null || true\n\
+         fi",
+        list
+    )
+}
+
+/// rpm %post snippet, equivalent to `%systemd_post`: apply presets on first install.
+pub fn rpm_post_snippet(metadata: &Metadata) -> String {
+    let units = unit_names(metadata);
+    if units.is_empty() {
+        return String::new();
+    }
+    format!(
+        "if [ \"$1\" -eq 1 ]; then\n\
+         systemctl --no-reload preset {} >/dev/null 2>&1 || :\n\
+         fi",
+        units.join(" ")
+    )
+}
+
+/// rpm %preun snippet, equivalent to `%systemd_preun`: disable and stop on removal.
+pub fn rpm_preun_snippet(metadata: &Metadata) -> String {
+    let units = unit_names(metadata);
+    if units.is_empty() {
+        return String::new();
+    }
+    format!(
+        "if [ \"$1\" -eq 0 ]; then\n\
+         systemctl --no-reload disable --now {} >/dev/null 2>&1 || :\n\
+         fi",
+        units.join(" ")
+    )
+}
+
+/// rpm %postun snippet, equivalent to `%systemd_postun_with_restart`.
+pub fn rpm_postun_snippet(metadata: &Metadata) -> String {
+    let units = unit_names(metadata);
+    if units.is_empty() {
+        return String::new();
+    }
+    format!(
+        "systemctl daemon-reload >/dev/null 2>&1 || :\n\
+         if [ \"$1\" -ge 1 ]; then\n\
+         systemctl try-restart {} >/dev/null 2>&1 || :\n\
+         fi",
+        units.join(" ")
+    )
+}
+
+fn quoted_list(units: &[String]) -> String {
+    units
+        .iter()
+        .map(|unit| format!("'{}'", unit))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn metadata(users: &[&str]) -> Metadata {
+        Metadata {
+            name: "twincan".to_string(),
+            users: users
+                .iter()
+                .enumerate()
+                .map(|(index, line)| (index + 1, line.to_string()))
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn user_comments_are_quoted() {
+        for (comment, expected) in [
+            ("TwinCAN daemon", r#""TwinCAN daemon""#),
+            (r#"Say "hi""#, r#""Say \"hi\"""#),
+            (r"back\slash", r#""back\\slash""#),
+            ("100% $HOME `id`", r#""100%% $HOME `id`""#),
+        ] {
+            let line = format!("user twincan -u 950 -c '{}' -d /var/lib/twincan", comment);
+            let (sysusers, _) = users_config(&metadata(&[&line])).unwrap();
+            assert_eq!(
+                sysusers,
+                format!("u twincan 950 {} /var/lib/twincan -\n", expected),
+                "{}",
+                comment
+            );
+        }
+    }
+
+    #[test]
+    fn user_comment_errors() {
+        let error = users_config(&metadata(&["user twincan -c 'a:b'"]))
+            .unwrap_err()
+            .to_string();
+        assert_eq!(
+            error,
+            "line 1: The comment may not contain ':' or control characters: \
+             user twincan -c 'a:b'"
+        );
+        assert!(users_config(&metadata(&["user twincan -c 'a\tb'"])).is_err());
+    }
+
+    #[test]
+    fn users_snippet_is_not_expanded() {
+        let metadata = metadata(&[r#"user twincan -c "$(reboot) \"x\"""#]);
+        let snippet = create_users_snippet(&metadata).unwrap();
+        // The quoted heredoc delimiter keeps the shell from expanding the comment
+        assert!(
+            snippet.contains("<<'EOF'\nu twincan - \"$(reboot) \\\"x\\\"\" - -\nEOF\n"),
+            "{}",
+            snippet
+        );
+    }
+}
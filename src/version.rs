@@ -0,0 +1,583 @@
+use crate::metadata::Metadata;
+use std::cmp::Ordering;
+use std::str::FromStr;
+
+/// Package formats whose version grammar and comparison rules differ.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VersionFormat {
+    Deb,
+    Rpm,
+    Alpm,
+}
+
+impl FromStr for VersionFormat {
+    type Err = Box<dyn std::error::Error>;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "deb" => Ok(Self::Deb),
+            "rpm" => Ok(Self::Rpm),
+            "alpm" | "pacman" => Ok(Self::Alpm),
+            _ => Err(format!("Unknown version format '{}' (expected deb, rpm or alpm)", s).into()),
+        }
+    }
+}
+
+/// `[epoch:]version-release`, the full version as written by deb, RPM and ALPM alike.
+pub fn full_version(metadata: &Metadata) -> String {
+    if metadata.epoch.is_empty() || metadata.epoch == "0" {
+        format!("{}-{}", metadata.version, metadata.release)
+    } else {
+        format!(
+            "{}:{}-{}",
+            metadata.epoch, metadata.version, metadata.release
+        )
+    }
+}
+
+/// Check `version` and `release` against the grammar of `format`.
+pub fn validate(
+    format: VersionFormat,
+    metadata: &Metadata,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let version = &metadata.version;
+    let release = &metadata.release;
+    let invalid = |what: &str, value: &str, reason: &str| -> Box<dyn std::error::Error> {
+        format!("Invalid {:?} {} '{}': {}", format, what, value, reason).into()
+    };
+
+    match format {
+        VersionFormat::Deb => {
+            // deb-version(7): upstream starts with a digit, then [A-Za-z0-9.+~-]
+            if !version.starts_with(|c: char| c.is_ascii_digit()) {
+                return Err(invalid("version", version, "must start with a digit"));
+            }
+            if let Some(c) = version
+                .chars()
+                .find(|c| !(c.is_ascii_alphanumeric() || ".+~-".contains(*c)))
+            {
+                return Err(invalid(
+                    "version",
+                    version,
+                    &format!("'{}' is not allowed", c),
+                ));
+            }
+            if release.is_empty() {
+                return Err(invalid("release", release, "must not be empty"));
+            }
+            if let Some(c) = release
+                .chars()
+                .find(|c| !(c.is_ascii_alphanumeric() || "+.~".contains(*c)))
+            {
+                return Err(invalid(
+                    "release",
+                    release,
+                    &format!("'{}' is not allowed", c),
+                ));
+            }
+        }
+        VersionFormat::Rpm => {
+            // Version and Release tags: alphanumerics and ._+~^, no '-'
+            for (what, value) in [("version", version), ("release", release)] {
+                if value.is_empty() {
+                    return Err(invalid(what, value, "must not be empty"));
+                }
+                if let Some(c) = value
+                    .chars()
+                    .find(|c| !(c.is_ascii_alphanumeric() || "._+~^".contains(*c)))
+                {
+                    return Err(invalid(what, value, &format!("'{}' is not allowed", c)));
+                }
+            }
+        }
+        VersionFormat::Alpm => {
+            // alpm-pkgver(7): printable ASCII without ':', '/', '-' or whitespace
+            if version.is_empty() {
+                return Err(invalid("version", version, "must not be empty"));
+            }
+            if let Some(c) = version
+                .chars()
+                .find(|c| !c.is_ascii_graphic() || ":/-".contains(*c))
+            {
+                return Err(invalid(
+                    "version",
+                    version,
+                    &format!("'{}' is not allowed", c),
+                ));
+            }
+            // alpm-pkgrel(7): a number with an optional `.number` suffix
+            let valid_part = |p: &str| !p.is_empty() && p.chars().all(|c| c.is_ascii_digit());
+            let valid_release = match release.split_once('.') {
+                Some((major, minor)) => valid_part(major) && valid_part(minor),
+                None => valid_part(release),
+            };
+            if !valid_release {
+                return Err(invalid(
+                    "release",
+                    release,
+                    "must be a number like 1 or 1.1",
+                ));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Compare two full versions with the algorithm of `format`.
+pub fn vercmp(format: VersionFormat, a: &str, b: &str) -> Ordering {
+    match format {
+        VersionFormat::Deb => deb_vercmp(a, b),
+        VersionFormat::Rpm => rpm_evr_cmp(a, b),
+        VersionFormat::Alpm => alpm_vercmp(a, b),
+    }
+}
+
+/// Split `[epoch:]version[-release]` into its parts; `release` splits at the last '-'.
+fn split_evr(full: &str) -> (&str, &str, Option<&str>) {
+    let (epoch, rest) = match full.split_once(':') {
+        Some(("", rest)) => ("0", rest),
+        Some((epoch, rest)) if epoch.chars().all(|c| c.is_ascii_digit()) => (epoch, rest),
+        _ => ("0", full),
+    };
+    match rest.rsplit_once('-') {
+        Some((version, release)) => (epoch, version, Some(release)),
+        None => (epoch, rest, None),
+    }
+}
+
+fn cmp_epoch(a: &str, b: &str) -> Ordering {
+    let a = a.trim_start_matches('0');
+    let b = b.trim_start_matches('0');
+    a.len().cmp(&b.len()).then_with(|| a.cmp(b))
+}
+
+// -------- dpkg --------
+
+fn deb_vercmp(a: &str, b: &str) -> Ordering {
+    let (epoch_a, version_a, release_a) = split_evr(a);
+    let (epoch_b, version_b, release_b) = split_evr(b);
+    cmp_epoch(epoch_a, epoch_b)
+        .then_with(|| verrevcmp(version_a, version_b))
+        .then_with(|| verrevcmp(release_a.unwrap_or(""), release_b.unwrap_or("")))
+}
+
+/// Sort weight of a non-digit character in dpkg's comparison: `~` sorts before everything
+/// (even the end of the string), letters before other characters.
+fn dpkg_order(c: Option<u8>) -> i32 {
+    match c {
+        None => 0,
+        Some(c) if c.is_ascii_digit() => 0,
+        Some(c) if c.is_ascii_alphabetic() => c as i32,
+        Some(b'~') => -1,
+        Some(c) => c as i32 + 256,
+    }
+}
+
+/// dpkg's `verrevcmp`: alternate non-digit and digit runs.
+fn verrevcmp(a: &str, b: &str) -> Ordering {
+    let (a, b) = (a.as_bytes(), b.as_bytes());
+    let (mut i, mut j) = (0, 0);
+
+    while i < a.len() || j < b.len() {
+        while (i < a.len() && !a[i].is_ascii_digit()) || (j < b.len() && !b[j].is_ascii_digit()) {
+            let ac = dpkg_order(a.get(i).copied());
+            let bc = dpkg_order(b.get(j).copied());
+            if ac != bc {
+                return ac.cmp(&bc);
+            }
+            i += 1;
+            j += 1;
+        }
+
+        while a.get(i) == Some(&b'0') {
+            i += 1;
+        }
+        while b.get(j) == Some(&b'0') {
+            j += 1;
+        }
+
+        let mut first_diff = Ordering::Equal;
+        while i < a.len() && a[i].is_ascii_digit() && j < b.len() && b[j].is_ascii_digit() {
+            if first_diff == Ordering::Equal {
+                first_diff = a[i].cmp(&b[j]);
+            }
+            i += 1;
+            j += 1;
+        }
+        if i < a.len() && a[i].is_ascii_digit() {
+            return Ordering::Greater;
+        }
+        if j < b.len() && b[j].is_ascii_digit() {
+            return Ordering::Less;
+        }
+        if first_diff != Ordering::Equal {
+            return first_diff;
+        }
+    }
+
+    Ordering::Equal
+}
+
+// -------- RPM --------
+
+fn rpm_evr_cmp(a: &str, b: &str) -> Ordering {
+    let (epoch_a, version_a, release_a) = split_evr(a);
+    let (epoch_b, version_b, release_b) = split_evr(b);
+    cmp_epoch(epoch_a, epoch_b)
+        .then_with(|| rpmvercmp(version_a, version_b))
+        .then_with(|| match (release_a, release_b) {
+            (Some(ra), Some(rb)) => rpmvercmp(ra, rb),
+            _ => Ordering::Equal,
+        })
+}
+
+/// Compare one numeric or alphabetic segment; numbers beat letters, and numbers compare by
+/// value with leading zeros ignored.
+fn cmp_segments(a: &[u8], b: &[u8], numeric: bool) -> Ordering {
+    if numeric {
+        let a = &a[a.iter().position(|&c| c != b'0').unwrap_or(a.len())..];
+        let b = &b[b.iter().position(|&c| c != b'0').unwrap_or(b.len())..];
+        a.len().cmp(&b.len()).then_with(|| a.cmp(b))
+    } else {
+        a.cmp(b)
+    }
+}
+
+/// Take the leading run of digits (if `s` starts with one) or letters.
+fn take_segment(s: &[u8], numeric: bool) -> usize {
+    s.iter()
+        .position(|c| {
+            if numeric {
+                !c.is_ascii_digit()
+            } else {
+                !c.is_ascii_alphabetic()
+            }
+        })
+        .unwrap_or(s.len())
+}
+
+/// rpm's `rpmvercmp`, including `~` (sorts before anything) and `^` (sorts after the end).
+fn rpmvercmp(a: &str, b: &str) -> Ordering {
+    if a == b {
+        return Ordering::Equal;
+    }
+    let (mut one, mut two) = (a.as_bytes(), b.as_bytes());
+    let is_sep = |c: &u8| !c.is_ascii_alphanumeric() && *c != b'~' && *c != b'^';
+
+    while !one.is_empty() || !two.is_empty() {
+        while one.first().is_some_and(is_sep) {
+            one = &one[1..];
+        }
+        while two.first().is_some_and(is_sep) {
+            two = &two[1..];
+        }
+
+        if one.first() == Some(&b'~') || two.first() == Some(&b'~') {
+            if one.first() != Some(&b'~') {
+                return Ordering::Greater;
+            }
+            if two.first() != Some(&b'~') {
+                return Ordering::Less;
+            }
+            one = &one[1..];
+            two = &two[1..];
+            continue;
+        }
+
+        if one.first() == Some(&b'^') || two.first() == Some(&b'^') {
+            if one.is_empty() {
+                return Ordering::Less;
+            }
+            if two.is_empty() {
+                return Ordering::Greater;
+            }
+            if one.first() != Some(&b'^') {
+                return Ordering::Greater;
+            }
+            if two.first() != Some(&b'^') {
+                return Ordering::Less;
+            }
+            one = &one[1..];
+            two = &two[1..];
+            continue;
+        }
+
+        if one.is_empty() || two.is_empty() {
+            break;
+        }
+
+        let numeric = one[0].is_ascii_digit();
+        let len_one = take_segment(one, numeric);
+        let len_two = take_segment(two, numeric);
+        if len_two == 0 {
+            // Segments of different types: numbers are newer
+            return if numeric {
+                Ordering::Greater
+            } else {
+                Ordering::Less
+            };
+        }
+
+        let ordering = cmp_segments(&one[..len_one], &two[..len_two], numeric);
+        if ordering != Ordering::Equal {
+            return ordering;
+        }
+        one = &one[len_one..];
+        two = &two[len_two..];
+    }
+
+    match (one.is_empty(), two.is_empty()) {
+        (true, true) => Ordering::Equal,
+        (true, false) => Ordering::Less,
+        _ => Ordering::Greater,
+    }
+}
+
+// -------- pacman --------
+
+fn alpm_vercmp(a: &str, b: &str) -> Ordering {
+    if a == b {
+        return Ordering::Equal;
+    }
+    let (epoch_a, version_a, release_a) = split_evr(a);
+    let (epoch_b, version_b, release_b) = split_evr(b);
+    alpm_rpmvercmp(epoch_a, epoch_b)
+        .then_with(|| alpm_rpmvercmp(version_a, version_b))
+        .then_with(|| match (release_a, release_b) {
+            (Some(ra), Some(rb)) => alpm_rpmvercmp(ra, rb),
+            _ => Ordering::Equal,
+        })
+}
+
+/// libalpm's variant of `rpmvercmp`: no `~`/`^` handling, separator lengths matter, and a
+/// trailing alphabetic segment is older than the end of the string.
+fn alpm_rpmvercmp(a: &str, b: &str) -> Ordering {
+    if a == b {
+        return Ordering::Equal;
+    }
+    let (mut one, mut two) = (a.as_bytes(), b.as_bytes());
+
+    while !one.is_empty() && !two.is_empty() {
+        let sep_one = one
+            .iter()
+            .position(|c| c.is_ascii_alphanumeric())
+            .unwrap_or(one.len());
+        let sep_two = two
+            .iter()
+            .position(|c| c.is_ascii_alphanumeric())
+            .unwrap_or(two.len());
+        one = &one[sep_one..];
+        two = &two[sep_two..];
+
+        if one.is_empty() || two.is_empty() {
+            break;
+        }
+        if sep_one != sep_two {
+            return sep_one.cmp(&sep_two);
+        }
+
+        let numeric = one[0].is_ascii_digit();
+        let len_one = take_segment(one, numeric);
+        let len_two = take_segment(two, numeric);
+        if len_two == 0 {
+            return if numeric {
+                Ordering::Greater
+            } else {
+                Ordering::Less
+            };
+        }
+
+        let ordering = cmp_segments(&one[..len_one], &two[..len_two], numeric);
+        if ordering != Ordering::Equal {
+            return ordering;
+        }
+        one = &one[len_one..];
+        two = &two[len_two..];
+    }
+
+    if one.is_empty() && two.is_empty() {
+        return Ordering::Equal;
+    }
+    let two_is_alpha = two.first().is_some_and(|c| c.is_ascii_alphabetic());
+    let one_is_alpha = one.first().is_some_and(|c| c.is_ascii_alphabetic());
+    if (one.is_empty() && !two_is_alpha) || one_is_alpha {
+        Ordering::Less
+    } else {
+        Ordering::Greater
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn check(format: VersionFormat, cases: &[(&str, &str, i8)]) {
+        for &(a, b, expected) in cases {
+            let expected = expected.cmp(&0);
+            assert_eq!(vercmp(format, a, b), expected, "{:?} {} {}", format, a, b);
+            assert_eq!(
+                vercmp(format, b, a),
+                expected.reverse(),
+                "{:?} {} {}",
+                format,
+                b,
+                a
+            );
+        }
+    }
+
+    // Cases from dpkg's libdpkg version tests and deb-version(7)
+    #[test]
+    fn deb_reference_cases() {
+        check(
+            VersionFormat::Deb,
+            &[
+                ("1.0", "1.0", 0),
+                ("1.0", "1.1", -1),
+                ("2.30", "2.4", 1),
+                ("1.0~rc1", "1.0", -1),
+                ("1.0~", "1.0", -1),
+                ("1.0~~", "1.0~", -1),
+                ("1.0~~a", "1.0~~", 1),
+                ("1.0", "1.0+b1", -1),
+                ("1.0", "1.0.", -1),
+                ("1.0a", "1.0", 1),
+                ("1.0+dfsg", "1.0.1", -1),
+                ("1:1.0", "2.0", 1),
+                ("0:1.0", "1.0", 0),
+                ("1.0-1", "1.0-2", -1),
+                ("1.0-1~bpo1", "1.0-1", -1),
+                ("1.0-0", "1.0", 0),
+                ("1.0-1ubuntu1", "1.0-1", 1),
+            ],
+        );
+    }
+
+    // Cases from rpm's rpmvercmp.at
+    #[test]
+    fn rpm_reference_cases() {
+        check(
+            VersionFormat::Rpm,
+            &[
+                ("1.0", "1.0", 0),
+                ("1.0", "2.0", -1),
+                ("2.0", "2.0.1", -1),
+                ("2.0.1a", "2.0.1", 1),
+                ("5.5p1", "5.5p2", -1),
+                ("5.5p1", "5.5p10", -1),
+                ("10xyz", "10.1xyz", -1),
+                ("xyz10", "xyz10.1", -1),
+                ("xyz.4", "8", -1),
+                ("xyz.4", "2", -1),
+                ("5.5p2", "5.6p1", -1),
+                ("5.6p1", "6.5p1", -1),
+                ("6.0.rc1", "6.0", 1),
+                ("10b2", "10a1", 1),
+                ("10a2", "10b2", -1),
+                ("1.0a", "1.0aa", -1),
+                ("10.0001", "10.1", 0),
+                ("10.0001", "10.0039", -1),
+                ("4.999.9", "5.0", -1),
+                ("20101121", "20101122", -1),
+                ("2.0", "2_0", 0),
+                ("a+", "a_", 0),
+                ("+a", "_a", 0),
+                ("+_", "_+", 0),
+                ("1.0~rc1", "1.0", -1),
+                ("1.0~rc1", "1.0~rc2", -1),
+                ("1.0~rc1~git123", "1.0~rc1", -1),
+                ("1.0^", "1.0", 1),
+                ("1.0^git1", "1.0", 1),
+                ("1.0^git1", "1.0.1", -1),
+                ("1.0^git1", "1.0^git2", -1),
+                ("1.0^git1", "1.01", -1),
+                ("1.0^20160101", "1.0.1", -1),
+                ("1.0~rc1^git1", "1.0~rc1", 1),
+                ("1.0^git1~pre", "1.0^git1", -1),
+                ("1:1.0-1", "2.0-1", 1),
+                ("1.0-1", "1.0-2", -1),
+            ],
+        );
+    }
+
+    // Cases from pacman's vercmptest.sh
+    #[test]
+    fn alpm_reference_cases() {
+        check(
+            VersionFormat::Alpm,
+            &[
+                ("1.5.0", "1.5.0", 0),
+                ("1.5.1", "1.5.0", 1),
+                ("1.5.1", "1.5", 1),
+                ("1.5.0-1", "1.5.0-2", -1),
+                ("1.5.0-2", "1.5.1-1", -1),
+                ("1.5-1", "1.5", 0),
+                ("1.0-1", "1.1", -1),
+                ("1.5b-1", "1.5-1", -1),
+                ("1.5b", "1.5", -1),
+                ("1.5b", "1.5.1", -1),
+                ("1.0a", "1.0alpha", -1),
+                ("1.0alpha", "1.0b", -1),
+                ("1.0b", "1.0beta", -1),
+                ("1.0beta", "1.0rc", -1),
+                ("1.0rc", "1.0", -1),
+                ("1.5.a", "1.5", 1),
+                ("1.5.b", "1.5.a", 1),
+                ("1.5.1", "1.5.b", 1),
+                ("1.5.b-1", "1.5.b", 0),
+                ("1.5-1", "1.5.b", -1),
+                ("2.0", "2_0", 0),
+                ("2.0_a", "2_0.a", 0),
+                ("2.0a", "2.0.a", -1),
+                ("2___a", "2_a", 1),
+                ("0:1.0", "0:1.1", -1),
+                ("1:1.0", "0:1.1", 1),
+                ("1:1.0", "2:1.1", -1),
+                ("1:1.0-1", "0:1.1-1", 1),
+                ("0:1.1", "1.1", 0),
+                ("1:1.1", "1.1", 1),
+            ],
+        );
+    }
+
+    #[test]
+    fn validate_rejects_invalid_versions() {
+        let metadata = |version: &str, release: &str| Metadata {
+            version: version.to_string(),
+            release: release.to_string(),
+            ..Default::default()
+        };
+        for (format, version, release, valid) in [
+            (VersionFormat::Deb, "1.0~rc1+dfsg-2", "1ubuntu1", true),
+            (VersionFormat::Deb, "a1.0", "1", false),
+            (VersionFormat::Deb, "1.0_1", "1", false),
+            (VersionFormat::Deb, "1:0", "1", false),
+            (VersionFormat::Deb, "1.0", "", false),
+            (VersionFormat::Deb, "1.0", "1-1", false),
+            (VersionFormat::Rpm, "1.0~rc1^git1", "1.fc40", true),
+            (VersionFormat::Rpm, "1.0-1", "1", false),
+            (VersionFormat::Rpm, "1.0 beta", "1", false),
+            (VersionFormat::Rpm, "", "1", false),
+            (VersionFormat::Rpm, "1.0", "", false),
+            (VersionFormat::Alpm, "1.0.r12.gabc_1+x", "1.1", true),
+            (VersionFormat::Alpm, "1:0", "1", false),
+            (VersionFormat::Alpm, "1.0-1", "1", false),
+            (VersionFormat::Alpm, "1 0", "1", false),
+            (VersionFormat::Alpm, "", "1", false),
+            (VersionFormat::Alpm, "1.0", "a", false),
+            (VersionFormat::Alpm, "1.0", "1.", false),
+            (VersionFormat::Alpm, "1.0", "1.1.1", false),
+        ] {
+            let result = validate(format, &metadata(version, release));
+            assert_eq!(
+                result.is_ok(),
+                valid,
+                "{:?} {}-{}",
+                format,
+                version,
+                release
+            );
+        }
+    }
+}